@@ -1,6 +1,7 @@
 use bevy::{gltf::GltfLoaderSettings, prelude::*, render::RenderPlugin, scene::SceneInstance, window::WindowResolution};
 use bevy_flycam::prelude::*;
-use bevy_capture::{encoder::frames, CameraTargetHeadless, Capture, CaptureBundle};
+use bevy_capture::{encoder::{frames, per_view::PerViewEncoder}, events::EncodeFailed, layers::{DebugVisual, DEBUG_RENDER_LAYER}, CameraTargetHeadless, Capture, CaptureGroup, Encoder};
+use bevy::render::view::RenderLayers;
 use std::{f32::consts::TAU, fs};
 use std::sync::atomic::{AtomicU8, Ordering};
 use bevy::color::palettes::basic::WHITE;
@@ -8,15 +9,29 @@ use bevy::color::palettes::css::ORANGE_RED;
 use bevy::core_pipeline::bloom::Bloom;
 use bevy::pbr::CascadeShadowConfigBuilder;
 use bevy_capture::animation::{keyboard_animation_control, setup_animation, setup_scene_once_loaded};
-use bevy_capture::encoder::mem_encoder::MyCustomEncoder;
+use bevy_capture::encoder::{mem_encoder::{serde_json::{json, Value}, write_manifest, ManifestCamera, MyCustomEncoder, ShmEncoderConfig}, PixelFormat};
 use shared_memory::ShmemConf;
 
+// Resolution of the data cameras and their camN_frame segments
+const FRAME_WIDTH: u32 = 512;
+const FRAME_HEIGHT: u32 = 512;
+// Full color frames, PixelFormat::L8 emulates the monochrome IR cameras at a quarter of the size
+const FRAME_FORMAT: PixelFormat = PixelFormat::Rgba8;
+
+const LINE_BUFFER_NAME: &str = "bevy_line_input_app";
+const POSE_BUFFER_NAME: &str = "bevy_pose_input_app";
+const MAX_LINES: usize = 512;
+const MAX_POSES: usize = 512;
+
+// Consumers discover the segments here instead of hard-coding them
+const MANIFEST_PATH: &str = "captures/shm_manifest.json";
+
 fn main() -> AppExit {
     // Create the captures directory
     fs::create_dir_all("captures/simple").unwrap();
     // let enc = MyCustomEncoder::new("fuck_this_shit", 512, 512);
-    let line_buffer_app = LineBuffer::new("bevy_line_input_app", 512);
-    let pose_buffer_app = PoseBuffer::new("bevy_pose_input_app", 512);
+    let line_buffer_app = LineBuffer::new(LINE_BUFFER_NAME, MAX_LINES);
+    let pose_buffer_app = PoseBuffer::new(POSE_BUFFER_NAME, MAX_POSES);
     // let pose_buffer_app = PoseBuffer::new("bevy_line_input_app", 512);
     
 
@@ -36,7 +51,8 @@ fn main() -> AppExit {
             }), 
             ..default() 
         }),
-        bevy_capture::CapturePlugin,
+        bevy_capture::CapturePlugin::default(),
+        bevy_capture::stats::CaptureStatsLogPlugin::default(),
     ));
 
     app.add_plugins(NoCameraPlayerPlugin);
@@ -52,6 +68,7 @@ fn main() -> AppExit {
         .add_systems(Update, keyboard_animation_control);
 
     app.add_systems(Update, toggle_gizmos_on_top);
+    app.add_systems(Startup, configure_debug_gizmos);
 
 
     app.insert_resource(MovementSettings {
@@ -83,6 +100,7 @@ fn main() -> AppExit {
 
     app.add_systems(Update, toggle_recording);
     app.add_systems(Update, monitor_recording);
+    app.add_systems(Update, stop_recording_on_error);
 
     app.add_systems(Update, wait_for_all_scenes_ready);
 
@@ -130,6 +148,27 @@ fn monitor_recording(recording: Res<Recording>) {
     }
 }
 
+fn stop_recording_on_error(
+    mut encode_failed: EventReader<EncodeFailed>,
+    mut captures: Query<&mut Capture>,
+    mut groups: Query<&mut CaptureGroup>,
+    mut recording: ResMut<Recording>,
+) {
+    let Some(event) = encode_failed.read().next() else {
+        return;
+    };
+    error!("Stopping the recording, capture {} failed: {}", event.entity, event.error);
+    encode_failed.clear();
+
+    for mut capture in &mut captures {
+        capture.stop();
+    }
+    for mut group in &mut groups {
+        group.stop();
+    }
+    recording.active = false;
+}
+
 fn setup_3d(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
@@ -241,6 +280,8 @@ fn wait_for_all_scenes_ready(
             ];
 
             let mut i = 0;
+            let mut data_cameras = Vec::new();
+            let mut manifest_cameras = Vec::new();
             for (name, transform, entity) in &scene_objects_query {
                 println!("Entity: {} | Transform: T{:?} R{:?}", name.as_str(), transform.translation, Mat3::from_quat(transform.rotation));
                 if name.as_str().starts_with("data_camera") {
@@ -249,23 +290,38 @@ fn wait_for_all_scenes_ready(
                         Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
                         MeshMaterial3d(materials.add(color)),
                         Transform::from_translation(Vec3::ZERO),
+                        DebugVisual,
                     )).insert(ChildOf(entity));
 
-                    commands.spawn(
+                    let data_camera = commands.spawn(
                         (
                             Camera3d::default(),
                             bevy::core_pipeline::tonemapping::Tonemapping::AcesFitted,
                             Transform::from_translation(Vec3::ZERO),
-                            Camera::default().target_headless(512, 512, &mut images),
-                            CaptureBundle::default(),
+                            Camera::default().target_headless(FRAME_WIDTH, FRAME_HEIGHT, &mut images),
                         )
-                    ).insert(ChildOf(entity));
+                    ).insert(ChildOf(entity)).id();
+                    data_cameras.push(data_camera);
+                    manifest_cameras.push(ManifestCamera {
+                        index: i,
+                        name: name.as_str().to_string(),
+                        segment: frame_segment_name(i),
+                        width: FRAME_WIDTH,
+                        height: FRAME_HEIGHT,
+                        config: frame_config(),
+                    });
 
                     commands.entity(entity).insert(CameraIndex { index: i });
 
                     i = i + 1;
                 }
             }
+
+            // Capture all data cameras in lockstep, so camN_frame always shows the same tick
+            commands.spawn(CaptureGroup::new(data_cameras));
+            if let Err(e) = write_manifest(MANIFEST_PATH, &manifest_cameras, buffers_manifest()) {
+                error!("Failed to write the manifest {}: {}", MANIFEST_PATH, e);
+            }
             *has_run = true;
         } else {
             println!("Scene NOT ready: {:?}", **instance);
@@ -273,6 +329,27 @@ fn wait_for_all_scenes_ready(
     }
 }
 
+/// The configuration of the segments the frames of the data cameras are written to.
+fn frame_config() -> ShmEncoderConfig {
+    ShmEncoderConfig {
+        format: FRAME_FORMAT.into(),
+        ..default()
+    }
+}
+
+/// The name of the segment the frames of the data camera with the given index are written to.
+fn frame_segment_name(index: usize) -> String {
+    format!("cam{}_frame", index)
+}
+
+/// Describes the line and pose buffers in the manifest, next to the frame segments.
+fn buffers_manifest() -> [(String, Value); 2] {
+    [
+        ("lines".into(), json!({ "segment": LINE_BUFFER_NAME, "max_lines": MAX_LINES, "layout": "f32 start xyz, end xyz" })),
+        ("poses".into(), json!({ "segment": POSE_BUFFER_NAME, "max_poses": MAX_POSES, "layout": "f32 translation xyz, rotation 3x3 column major, by camera index" })),
+    ]
+}
+
 // fn update(
 //     mut app_exit: EventWriter<AppExit>,
 //     mut capture: Query<&mut Capture>,
@@ -301,7 +378,7 @@ fn wait_for_all_scenes_ready(
 fn update(
     mut app_exit: EventWriter<AppExit>,
     time: Res<Time>,
-    mut capture: Query<&mut Capture>,
+    mut groups: Query<&mut CaptureGroup>,
     mut cubes: Query<&mut Transform, With<Cube>>,
     mut frame: Local<u32>,
     mut recording: ResMut<Recording>,
@@ -311,11 +388,15 @@ fn update(
     }
 
 
-    for (i, mut capture) in capture.iter_mut().enumerate() {
-        if !capture.is_capturing() {
-            let name = format!("cam{}_frame", i);
-            let encoder = MyCustomEncoder::new(&name, 512, 512);
-            capture.start(encoder);
+    for mut group in &mut groups {
+        if !group.is_capturing() {
+            let encoders: Vec<Box<dyn Encoder + Send + Sync>> = (0..group.members().len())
+                .map(|i| {
+                    let name = frame_segment_name(i);
+                    Box::new(MyCustomEncoder::new_with_config(&name, FRAME_WIDTH as usize, FRAME_HEIGHT as usize, frame_config())) as Box<dyn Encoder + Send + Sync>
+                })
+                .collect();
+            group.start(PerViewEncoder::new(encoders));
         }
     }
 
@@ -372,6 +453,12 @@ fn toggle_ambient_light_system(
     }
 }
 
+// Keep the gizmos out of the data cameras, they only show up in the viewer
+fn configure_debug_gizmos(mut config_store: ResMut<GizmoConfigStore>) {
+    let (config, _) = config_store.config_mut::<DefaultGizmoConfigGroup>();
+    config.render_layers = RenderLayers::layer(DEBUG_RENDER_LAYER);
+}
+
 fn toggle_gizmos_on_top(
     keyboard_input: Res<ButtonInput<KeyCode>>,
     mut config_store: ResMut<GizmoConfigStore>,
@@ -2,6 +2,7 @@ use bevy::{gltf::GltfLoaderSettings, prelude::*, render::RenderPlugin, scene::Sc
 use bevy_flycam::prelude::*;
 use bevy_capture::{
     encoder::frames,
+    events::EncodeFailed,
     CameraTargetHeadless, Capture, CaptureBundle,
 };
 use std::{f32::consts::TAU, fs};
@@ -27,7 +28,7 @@ fn main() -> AppExit {
             }), 
             ..default() 
         }),
-        bevy_capture::CapturePlugin,
+        bevy_capture::CapturePlugin::default(),
     ));
 
     app.add_plugins(NoCameraPlayerPlugin);
@@ -52,6 +53,7 @@ fn main() -> AppExit {
 
     app.add_systems(Update, toggle_recording);
     app.add_systems(Update, monitor_recording);
+    app.add_systems(Update, stop_recording_on_error);
 
     app.add_systems(Update, wait_for_all_scenes_ready);
 
@@ -65,23 +67,25 @@ struct Cube;
 #[derive(Resource, Default)]
 struct Recording {
     active: bool,
-    single: bool,
-    stop_next_frame: bool
 }
 
 fn toggle_recording(
     keyboard_input: Res<ButtonInput<KeyCode>>,
     mut recording: ResMut<Recording>,
+    mut capture: Query<&mut Capture>,
+    mut shots: Local<u32>,
 ) {
     if keyboard_input.just_pressed(KeyCode::Space) {
         recording.active = !recording.active;
         info!("Recording toggled: {}", recording.active);
     }
 
-    if keyboard_input.just_pressed(KeyCode::KeyP) {
-        recording.active = true;
-        recording.single = true;
-        info!("Recording Single: {} {}", recording.active, recording.single);
+    // Grab a single frame, the capture stops by itself once it has been delivered
+    if keyboard_input.just_pressed(KeyCode::KeyP) && !recording.active {
+        let mut capture = capture.single_mut().unwrap();
+        capture.capture_single(frames::FramesEncoder::new(format!("captures/simple/single_{:03}", *shots)));
+        *shots += 1;
+        info!("Recording Single: {}", *shots);
     }
 }
 
@@ -94,6 +98,23 @@ fn monitor_recording(recording: Res<Recording>) {
     }
 }
 
+fn stop_recording_on_error(
+    mut encode_failed: EventReader<EncodeFailed>,
+    mut captures: Query<&mut Capture>,
+    mut recording: ResMut<Recording>,
+) {
+    let Some(event) = encode_failed.read().next() else {
+        return;
+    };
+    error!("Stopping the recording, capture {} failed: {}", event.entity, event.error);
+    encode_failed.clear();
+
+    for mut capture in &mut captures {
+        capture.stop();
+    }
+    recording.active = false;
+}
+
 fn setup_3d(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
@@ -233,22 +254,6 @@ fn update(
         recording.active = false;
         // app_exit.write(AppExit::Success);
     }
-
-
-
-    if recording.stop_next_frame{
-        println!("set stopdqa");
-        recording.stop_next_frame = false;
-        capture.pause();
-        recording.active = false;
-    }
-
-    if recording.single {
-        recording.single = false;
-        println!("set stop_next_frame");
-        recording.stop_next_frame = true;  
-        capture.resume();
-    }
 }
 
 
@@ -0,0 +1,590 @@
+//! Detects bright marker blobs in the frames and outputs their centroids instead of the images.
+//!
+//! # Shared memory layout
+//!
+//! The blob segment of [`BlobDetector::with_shm`] holds the blobs of the latest frame. All
+//! integers and floats are native endian.
+//!
+//! The segment starts with a header of [`BLOB_SHM_HEADER_SIZE`] bytes:
+//!
+//! | Offset | Type      | Field                                              |
+//! |--------|-----------|----------------------------------------------------|
+//! | 0      | `[u8; 4]` | magic, [`BLOB_SHM_MAGIC`]                          |
+//! | 4      | `u32`     | version, [`BLOB_SHM_VERSION`]                      |
+//! | 8      | `u32`     | maximum number of blobs                            |
+//! | 12     | `u32`     | number of blobs of the frame                       |
+//! | 16     | `u64`     | seqlock, odd while the frame is written            |
+//! | 24     | `u64`     | id of the frame, see [`FrameContext::frame`]       |
+//! | 32     | `u64`     | elapsed simulation time in nanoseconds             |
+//! | 40     | `u32`     | width of the frame in pixels                       |
+//! | 44     | `u32`     | height of the frame in pixels                      |
+//! | 48     |           | reserved up to the end of the header               |
+//!
+//! It is followed by the blobs, largest first, each a record of [`BLOB_SHM_RECORD_SIZE`] bytes:
+//! the centroid as two `f32`s, the area as `u32`, the bounding box as four `u32`s (min x, min y,
+//! max x, max y) and four reserved bytes.
+//!
+//! The frames are written with the same seqlock as the frame segments of
+//! [`mem_encoder`](super::mem_encoder): frame `n`, counted from 1, stores `2n - 1` in the seqlock,
+//! writes the frame and stores `2n`. Readers copy the frame while the seqlock is even and retry
+//! if it changed in the meantime.
+
+use super::{
+    mem_encoder::{open_or_create_shm, u32_at, u64_at},
+    Encoder, FrameContext, FrameView, LumaWeights, OutputFormat, PixelFormat, Result,
+};
+use bevy::prelude::*;
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::atomic::{fence, Ordering},
+    time::Duration,
+};
+
+/// The magic bytes at the start of a blob segment.
+pub const BLOB_SHM_MAGIC: [u8; 4] = *b"BCBL";
+/// The version of the blob segment layout, increased with every incompatible change.
+pub const BLOB_SHM_VERSION: u32 = 1;
+/// The size of the header of a blob segment in bytes.
+pub const BLOB_SHM_HEADER_SIZE: usize = 64;
+/// The size of a blob record in a blob segment in bytes.
+pub const BLOB_SHM_RECORD_SIZE: usize = 32;
+
+// Offsets of the header fields
+const MAGIC: usize = 0;
+const VERSION: usize = 4;
+const MAX_BLOBS: usize = 8;
+const COUNT: usize = 12;
+const SEQLOCK: usize = 16;
+const FRAME: usize = 24;
+const TIMESTAMP: usize = 32;
+const WIDTH: usize = 40;
+const HEIGHT: usize = 44;
+
+/// A connected region of pixels at or above the threshold of a [`BlobDetector`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blob {
+    /// The centroid in pixels, weighted by how far the luminance of the pixels is above the
+    /// threshold. Pixels at the threshold have a weight of 1. The center of the top left pixel
+    /// is at `(0.5, 0.5)`.
+    pub centroid: Vec2,
+    /// The number of pixels.
+    pub area: u32,
+    /// The bounding box in pixels, `max` is exclusive.
+    pub bounds: URect,
+}
+
+/// Which neighbours of a pixel belong to the same blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Connectivity {
+    /// The horizontal and vertical neighbours.
+    Four,
+    /// The horizontal, vertical and diagonal neighbours, like the contours of OpenCV.
+    #[default]
+    Eight,
+}
+
+impl Connectivity {
+    fn neighbours(self) -> &'static [(isize, isize)] {
+        const FOUR: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+        const EIGHT: [(isize, isize); 8] = [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ];
+        match self {
+            Self::Four => &FOUR,
+            Self::Eight => &EIGHT,
+        }
+    }
+}
+
+/// The blobs detected in a single frame, as passed to the callbacks of a [`BlobDetector`].
+#[derive(Debug, Clone, Copy)]
+pub struct BlobFrame<'a> {
+    /// The id of the frame, see [`FrameContext::frame`].
+    pub frame: u64,
+    /// The elapsed time of the frame, see [`FrameContext::elapsed`].
+    pub elapsed: Duration,
+    /// The width of the frame in pixels.
+    pub width: u32,
+    /// The height of the frame in pixels.
+    pub height: u32,
+    /// The blobs, largest first.
+    pub blobs: &'a [Blob],
+}
+
+type Callback = Box<dyn FnMut(&BlobFrame<'_>) + Send + Sync>;
+
+/// An encoder that detects bright blobs, e.g. the markers seen by an IR camera, and outputs their
+/// centroids instead of the images.
+///
+/// Each frame is converted to its luminance and thresholded. The connected regions of pixels at
+/// or above the threshold within the area limits are the blobs, sorted by area, largest first.
+/// They are passed to any number of outputs:
+///
+/// - [`with_callback`](Self::with_callback): a function called with every frame
+/// - [`with_csv`](Self::with_csv): a CSV file with a row per blob
+/// - [`with_shm`](Self::with_shm): a shared memory segment holding the blobs of the latest frame
+///
+/// Without a frame context, i.e. through [`Encoder::encode`], the frames are numbered from `0`
+/// and their elapsed time is zero.
+///
+/// # Example
+/// ```ignore
+/// let detector = BlobDetector::new()
+///     .with_threshold(200)
+///     .with_area(4, Some(2000))
+///     .with_callback(|frame| info!("{} markers", frame.blobs.len()))
+///     .with_csv("captures/cam0_blobs.csv")?;
+/// capture.start(detector);
+/// ```
+pub struct BlobDetector {
+    threshold: u8,
+    luma: LumaWeights,
+    min_area: u32,
+    max_area: Option<u32>,
+    connectivity: Connectivity,
+
+    callbacks: Vec<Callback>,
+    csv: Option<BufWriter<File>>,
+    shm: Option<BlobShm>,
+
+    /// The number of frames encoded.
+    frame: u64,
+    blobs: Vec<Blob>,
+    /// Reused for the luminance of the frames.
+    luminance: Vec<u8>,
+    /// Reused for the pixels that were already assigned to a blob or rejected.
+    visited: Vec<bool>,
+    /// Reused for the flood fill.
+    stack: Vec<usize>,
+}
+
+impl Default for BlobDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlobDetector {
+    /// Creates a new blob detector with a threshold of `128`, the default [`LumaWeights`], no
+    /// area limits and [`Connectivity::Eight`]. Add outputs to consume the blobs.
+    pub fn new() -> Self {
+        Self {
+            threshold: 128,
+            luma: LumaWeights::default(),
+            min_area: 1,
+            max_area: None,
+            connectivity: Connectivity::default(),
+
+            callbacks: Vec::new(),
+            csv: None,
+            shm: None,
+
+            frame: 0,
+            blobs: Vec::new(),
+            luminance: Vec::new(),
+            visited: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Sets the threshold of the 8 bit luminance, pixels at or above it belong to blobs.
+    pub fn with_threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the weights the luminance is computed with.
+    pub fn with_luma(mut self, luma: LumaWeights) -> Self {
+        self.luma = luma;
+        self
+    }
+
+    /// Sets the minimum and maximum area of the blobs in pixels, `None` for no maximum. Smaller
+    /// and larger regions are ignored, e.g. noise and reflections.
+    pub fn with_area(mut self, min: u32, max: Option<u32>) -> Self {
+        self.min_area = min;
+        self.max_area = max;
+        self
+    }
+
+    /// Sets which neighbours of a pixel belong to the same blob.
+    pub fn with_connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.connectivity = connectivity;
+        self
+    }
+
+    /// Calls the given function with the blobs of every frame.
+    pub fn with_callback(
+        mut self,
+        callback: impl FnMut(&BlobFrame<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Writes the blobs into a CSV file at the given path, with a row per blob:
+    ///
+    /// `frame,elapsed,blob,x,y,area,min_x,min_y,max_x,max_y`
+    ///
+    /// The elapsed time is in seconds. Frames without blobs have no rows.
+    pub fn with_csv(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let mut csv = BufWriter::new(File::create(path)?);
+        writeln!(csv, "frame,elapsed,blob,x,y,area,min_x,min_y,max_x,max_y")?;
+        self.csv = Some(csv);
+        Ok(self)
+    }
+
+    /// Writes the blobs of the latest frame into the shared memory segment with the given name,
+    /// at most `max_blobs` of them. See the [module documentation](self) for the layout.
+    pub fn with_shm(mut self, name: &str, max_blobs: usize) -> Result<Self> {
+        self.shm = Some(BlobShm::new(name, max_blobs)?);
+        Ok(self)
+    }
+
+    /// Detects the blobs in the given frame, without passing them to the outputs.
+    pub fn detect(&mut self, frame: &FrameView<'_>) -> Result<&[Blob]> {
+        let Self {
+            threshold,
+            luma,
+            min_area,
+            max_area,
+            connectivity,
+            blobs,
+            luminance,
+            visited,
+            stack,
+            ..
+        } = self;
+
+        let format = OutputFormat::new(PixelFormat::L8).with_luma(*luma);
+        let luminance = frame.to_output(format, luminance)?;
+        let (width, height) = (frame.width as usize, frame.height as usize);
+
+        visited.clear();
+        visited.resize(width * height, false);
+        blobs.clear();
+
+        for start in 0..width * height {
+            if visited[start] || luminance[start] < *threshold {
+                continue;
+            }
+            visited[start] = true;
+            stack.push(start);
+
+            let (mut weight, mut sum_x, mut sum_y) = (0.0f64, 0.0f64, 0.0f64);
+            let mut area = 0u32;
+            let (mut min, mut max) = (UVec2::MAX, UVec2::ZERO);
+            while let Some(index) = stack.pop() {
+                let (x, y) = (index % width, index / width);
+
+                // Pixels at the threshold still count, so the weight is never zero
+                let pixel_weight = (luminance[index] - *threshold) as f64 + 1.0;
+                weight += pixel_weight;
+                sum_x += (x as f64 + 0.5) * pixel_weight;
+                sum_y += (y as f64 + 0.5) * pixel_weight;
+                area += 1;
+                min = min.min(UVec2::new(x as u32, y as u32));
+                max = max.max(UVec2::new(x as u32 + 1, y as u32 + 1));
+
+                for (dx, dy) in connectivity.neighbours() {
+                    let (nx, ny) = (x as isize + dx, y as isize + dy);
+                    if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                        continue;
+                    }
+                    let neighbour = ny as usize * width + nx as usize;
+                    if !visited[neighbour] && luminance[neighbour] >= *threshold {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+
+            if area >= *min_area && max_area.is_none_or(|max_area| area <= max_area) {
+                blobs.push(Blob {
+                    centroid: Vec2::new((sum_x / weight) as f32, (sum_y / weight) as f32),
+                    area,
+                    bounds: URect::from_corners(min, max),
+                });
+            }
+        }
+
+        blobs.sort_by_key(|blob| Reverse(blob.area));
+        Ok(blobs)
+    }
+
+    fn process(&mut self, frame: &FrameView<'_>, id: u64, elapsed: Duration) -> Result<()> {
+        self.detect(frame)?;
+
+        let blob_frame = BlobFrame {
+            frame: id,
+            elapsed,
+            width: frame.width,
+            height: frame.height,
+            blobs: &self.blobs,
+        };
+        for callback in &mut self.callbacks {
+            callback(&blob_frame);
+        }
+        if let Some(csv) = &mut self.csv {
+            for (index, blob) in blob_frame.blobs.iter().enumerate() {
+                writeln!(
+                    csv,
+                    "{},{:.6},{},{:.3},{:.3},{},{},{},{},{}",
+                    id,
+                    elapsed.as_secs_f64(),
+                    index,
+                    blob.centroid.x,
+                    blob.centroid.y,
+                    blob.area,
+                    blob.bounds.min.x,
+                    blob.bounds.min.y,
+                    blob.bounds.max.x,
+                    blob.bounds.max.y,
+                )?;
+            }
+        }
+        if let Some(shm) = &mut self.shm {
+            shm.write(&blob_frame);
+        }
+
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+impl Encoder for BlobDetector {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        self.process(&FrameView::of(image)?, self.frame, Duration::ZERO)
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, context: &FrameContext) -> Result<()> {
+        self.process(frame, context.frame, context.elapsed)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        if let Some(csv) = &mut self.csv {
+            csv.flush()
+                .map_err(|err| format!("Failed to write the blobs: {}", err))?;
+        }
+        Ok(())
+    }
+}
+
+/// The shared memory segment a [`BlobDetector`] writes the blobs of the latest frame into.
+struct BlobShm {
+    shmem: shared_memory::Shmem,
+    max_blobs: usize,
+    /// The sequence number of the last frame written.
+    sequence: u64,
+}
+
+impl BlobShm {
+    fn new(name: &str, max_blobs: usize) -> Result<Self> {
+        let size = BLOB_SHM_HEADER_SIZE + max_blobs * BLOB_SHM_RECORD_SIZE;
+        let shmem = open_or_create_shm(name, size)?;
+
+        // Invalidate the header while it is written, the magic is stored last
+        let base = shmem.as_ptr();
+        unsafe {
+            u32_at(base, MAGIC).store(0, Ordering::Relaxed);
+            fence(Ordering::Release);
+            u32_at(base, VERSION).store(BLOB_SHM_VERSION, Ordering::Relaxed);
+            u32_at(base, MAX_BLOBS).store(max_blobs as u32, Ordering::Relaxed);
+            u32_at(base, COUNT).store(0, Ordering::Relaxed);
+            u64_at(base, SEQLOCK).store(0, Ordering::Relaxed);
+            u32_at(base, MAGIC).store(u32::from_ne_bytes(BLOB_SHM_MAGIC), Ordering::Release);
+        }
+
+        Ok(Self {
+            shmem,
+            max_blobs,
+            sequence: 0,
+        })
+    }
+
+    fn write(&mut self, frame: &BlobFrame<'_>) {
+        let sequence = self.sequence + 1;
+        let blobs = &frame.blobs[..frame.blobs.len().min(self.max_blobs)];
+        let base = self.shmem.as_ptr();
+        let seqlock = unsafe { u64_at(base, SEQLOCK) };
+
+        // Mark the frame as being written
+        seqlock.store(2 * sequence - 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        unsafe {
+            u32_at(base, COUNT).store(blobs.len() as u32, Ordering::Relaxed);
+            u64_at(base, FRAME).store(frame.frame, Ordering::Relaxed);
+            u64_at(base, TIMESTAMP).store(frame.elapsed.as_nanos() as u64, Ordering::Relaxed);
+            u32_at(base, WIDTH).store(frame.width, Ordering::Relaxed);
+            u32_at(base, HEIGHT).store(frame.height, Ordering::Relaxed);
+        }
+
+        let records = unsafe { &mut self.shmem.as_slice_mut()[BLOB_SHM_HEADER_SIZE..] };
+        for (record, blob) in records.chunks_exact_mut(BLOB_SHM_RECORD_SIZE).zip(blobs) {
+            let fields = [
+                blob.centroid.x.to_ne_bytes(),
+                blob.centroid.y.to_ne_bytes(),
+                blob.area.to_ne_bytes(),
+                blob.bounds.min.x.to_ne_bytes(),
+                blob.bounds.min.y.to_ne_bytes(),
+                blob.bounds.max.x.to_ne_bytes(),
+                blob.bounds.max.y.to_ne_bytes(),
+                [0; 4],
+            ];
+            for (dst, field) in record.chunks_exact_mut(4).zip(fields) {
+                dst.copy_from_slice(&field);
+            }
+        }
+
+        // Complete the frame
+        seqlock.store(2 * sequence, Ordering::Release);
+        self.sequence = sequence;
+    }
+}
+
+unsafe impl Send for BlobShm {}
+unsafe impl Sync for BlobShm {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{test_context, test_view};
+
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 6;
+
+    /// Returns an RGBA8 frame that is black except for the given grey pixels.
+    fn frame(pixels: &[(u32, u32, u8)]) -> Vec<u8> {
+        let mut data = vec![0; (WIDTH * HEIGHT * 4) as usize];
+        for &(x, y, value) in pixels {
+            let index = ((y * WIDTH + x) * 4) as usize;
+            data[index..index + 4].copy_from_slice(&[value, value, value, 255]);
+        }
+        data
+    }
+
+    #[test]
+    fn separated_blobs() {
+        let data = frame(&[
+            (1, 1, 255),
+            (2, 1, 255),
+            (1, 2, 255),
+            (2, 2, 255),
+            (6, 4, 255),
+        ]);
+        let mut detector = BlobDetector::new();
+        let blobs = detector.detect(&test_view(WIDTH, HEIGHT, &data)).unwrap();
+
+        // Largest first
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].area, 4);
+        assert_eq!(blobs[0].centroid, Vec2::new(2.0, 2.0));
+        assert_eq!(blobs[0].bounds, URect::new(1, 1, 3, 3));
+        assert_eq!(blobs[1].area, 1);
+        assert_eq!(blobs[1].centroid, Vec2::new(6.5, 4.5));
+        assert_eq!(blobs[1].bounds, URect::new(6, 4, 7, 5));
+    }
+
+    #[test]
+    fn blob_at_the_edge() {
+        let data = frame(&[(7, 0, 255), (7, 1, 255), (0, 5, 255)]);
+        let mut detector = BlobDetector::new();
+        let blobs = detector.detect(&test_view(WIDTH, HEIGHT, &data)).unwrap();
+
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].area, 2);
+        assert_eq!(blobs[0].centroid, Vec2::new(7.5, 1.0));
+        assert_eq!(blobs[0].bounds, URect::new(7, 0, 8, 2));
+        assert_eq!(blobs[1].centroid, Vec2::new(0.5, 5.5));
+        assert_eq!(blobs[1].bounds, URect::new(0, 5, 1, 6));
+    }
+
+    #[test]
+    fn connectivity() {
+        // Two pixels that only touch diagonally
+        let data = frame(&[(2, 2, 255), (3, 3, 255)]);
+        let mut detector = BlobDetector::new();
+        assert_eq!(
+            detector
+                .detect(&test_view(WIDTH, HEIGHT, &data))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let mut detector = BlobDetector::new().with_connectivity(Connectivity::Four);
+        assert_eq!(
+            detector
+                .detect(&test_view(WIDTH, HEIGHT, &data))
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn area_filter() {
+        let data = frame(&[
+            (0, 0, 255),
+            (3, 3, 255),
+            (4, 3, 255),
+            (6, 0, 255),
+            (6, 1, 255),
+            (6, 2, 255),
+        ]);
+        let mut detector = BlobDetector::new().with_area(2, Some(2));
+        let blobs = detector.detect(&test_view(WIDTH, HEIGHT, &data)).unwrap();
+
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].area, 2);
+        assert_eq!(blobs[0].bounds, URect::new(3, 3, 5, 4));
+    }
+
+    #[test]
+    fn weighted_centroid() {
+        // The brighter pixel pulls the centroid towards it, with weights of 150 and 50 above the
+        // threshold: (2.5 * 150 + 3.5 * 50) / 200 = 2.75
+        let data = frame(&[(2, 2, 199), (3, 2, 99), (5, 5, 40)]);
+        let mut detector = BlobDetector::new().with_threshold(50);
+        let blobs = detector.detect(&test_view(WIDTH, HEIGHT, &data)).unwrap();
+
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].centroid, Vec2::new(2.75, 2.5));
+    }
+
+    #[test]
+    fn csv() {
+        let path =
+            std::env::temp_dir().join(format!("bevy_capture_blobs_{}.csv", std::process::id()));
+        let mut detector = BlobDetector::new()
+            .with_threshold(50)
+            .with_csv(&path)
+            .unwrap();
+
+        let data = frame(&[(2, 2, 199), (3, 2, 99), (6, 4, 255)]);
+        detector
+            .encode_view(&test_view(WIDTH, HEIGHT, &data), &test_context(7))
+            .unwrap();
+        Box::new(detector).finish().unwrap();
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            csv,
+            "frame,elapsed,blob,x,y,area,min_x,min_y,max_x,max_y\n\
+             7,0.070000,0,2.750,2.500,2,2,2,4,3\n\
+             7,0.070000,1,6.500,4.500,1,6,4,7,5\n"
+        );
+    }
+}
@@ -1,13 +1,19 @@
 //! Encode frames into individual images;
 
-use super::{Encoder, Result};
-use bevy::prelude::*;
+use super::{Encoder, FrameContext, FrameView, OutputFormat, Result};
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+use image::{ColorType, DynamicImage};
 use std::{fs, path::PathBuf};
 
 /// An encoder that encodes a sequence of images into individual images.
+///
+/// Frames are saved as PNG. Float frames keep their linear values and are saved as OpenEXR,
+/// which requires the `exr` feature, unless an output format is set.
 pub struct FramesEncoder {
     path: PathBuf,
     frame: u32,
+    format: Option<OutputFormat>,
+    buffer: Vec<u8>,
 }
 
 impl FramesEncoder {
@@ -16,19 +22,72 @@ impl FramesEncoder {
         Self {
             path: path.into(),
             frame: 0,
+            format: None,
+            buffer: Vec::new(),
         }
     }
-}
 
-impl Encoder for FramesEncoder {
-    fn encode(&mut self, image: &Image) -> Result<()> {
+    /// Saves all frames as PNG in the given format, e.g. [`L8`](super::PixelFormat::L8) for
+    /// grayscale frames. Float frames are clamped to `0..=1`.
+    pub fn with_output_format(mut self, format: impl Into<OutputFormat>) -> Self {
+        self.format = Some(format.into());
+        self
+    }
+
+    fn save(&mut self, frame: &FrameView<'_>) -> Result<()> {
         fs::create_dir_all(&self.path)?;
 
-        let image = image.clone().try_into_dynamic()?;
-        image.save(self.path.join(format!("frame_{:06}.png", self.frame)))?;
+        let path = self.path.join(format!("frame_{:06}", self.frame));
+        if let Some(format) = self.format {
+            image::save_buffer(
+                path.with_extension("png"),
+                frame.to_output(format, &mut self.buffer)?,
+                frame.width,
+                frame.height,
+                format.pixel_format.color_type(),
+            )?;
+        } else {
+            match frame.format {
+                // 8 bit color frames are saved without an intermediate image
+                TextureFormat::Rgba8Unorm
+                | TextureFormat::Rgba8UnormSrgb
+                | TextureFormat::Bgra8Unorm
+                | TextureFormat::Bgra8UnormSrgb => image::save_buffer(
+                    path.with_extension("png"),
+                    frame.to_rgba8(&mut self.buffer)?,
+                    frame.width,
+                    frame.height,
+                    ColorType::Rgba8,
+                )?,
+                _ => match frame.to_dynamic_image()? {
+                    image @ DynamicImage::ImageRgba32F(_) => save_float(&image, path)?,
+                    image => image.save(path.with_extension("png"))?,
+                },
+            }
+        }
 
         self.frame += 1;
 
         Ok(())
     }
 }
+
+impl Encoder for FramesEncoder {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        self.save(&FrameView::of(image)?)
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, _context: &FrameContext) -> Result<()> {
+        self.save(frame)
+    }
+}
+
+#[cfg(feature = "exr")]
+fn save_float(image: &DynamicImage, path: PathBuf) -> Result<()> {
+    Ok(image.save(path.with_extension("exr"))?)
+}
+
+#[cfg(not(feature = "exr"))]
+fn save_float(_image: &DynamicImage, _path: PathBuf) -> Result<()> {
+    Err("Saving float frames requires the `exr` feature".into())
+}
@@ -1,8 +1,8 @@
 //! Encodes frames into a gif.
 
-use super::{Encoder, Result};
+use super::{ensure_same_size, Encoder, FrameContext, FrameFormat, FrameView, Result};
 use bevy::prelude::*;
-use image::{codecs::gif, Frame};
+use image::{codecs::gif, Frame, RgbaImage};
 use std::io::Write;
 
 pub use gif::Repeat;
@@ -31,11 +31,28 @@ impl<W: Write> GifEncoder<W> {
     }
 }
 
-impl<W: Write> Encoder for GifEncoder<W> {
-    fn encode(&mut self, image: &Image) -> Result<()> {
-        let image = image.clone().try_into_dynamic()?;
-        let buffer = image.to_rgba8();
+impl<W: Write> GifEncoder<W> {
+    fn encode_rgba8(&mut self, frame: &FrameView<'_>) -> Result<()> {
+        // The gif frame takes ownership of the pixels
+        let pixels = frame.to_rgba8_vec()?;
+        let buffer = RgbaImage::from_raw(frame.width, frame.height, pixels)
+            .ok_or("Frame data does not match its size")?;
         self.0.encode_frame(Frame::new(buffer))?;
         Ok(())
     }
 }
+
+impl<W: Write> Encoder for GifEncoder<W> {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        self.encode_rgba8(&FrameView::of(image)?)
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, _context: &FrameContext) -> Result<()> {
+        self.encode_rgba8(frame)
+    }
+
+    fn format_changed(&mut self, from: FrameFormat, to: FrameFormat) -> Result<()> {
+        // The size of the gif is set by the first frame
+        ensure_same_size(from, to, "the gif encoder")
+    }
+}
@@ -1,40 +1,409 @@
-use super::{Encoder, Result};
-use bevy::prelude::*;
-use shared_memory::ShmemConf;
+//! Shares the captured frames with other processes through shared memory.
+//!
+//! [`MyCustomEncoder`] writes the frames into a named shared memory segment and
+//! [`ShmFrameReader`] reads them on the other side. All integers are native endian, i.e. little
+//! endian on every supported platform.
+//!
+//! # Layout
+//!
+//! The segment starts with a header of [`SHM_HEADER_SIZE`] bytes:
+//!
+//! | Offset | Type      | Field                                                          |
+//! |--------|-----------|----------------------------------------------------------------|
+//! | 0      | `[u8; 4]` | magic, [`SHM_MAGIC`]                                           |
+//! | 4      | `u32`     | version, [`SHM_VERSION`]                                       |
+//! | 8      | `u32`     | size of the header in bytes                                    |
+//! | 12     | `u32`     | width of the frames in pixels                                  |
+//! | 16     | `u32`     | height of the frames in pixels                                 |
+//! | 20     | `u32`     | pixel format, see below                                        |
+//! | 24     | `u32`     | number of slots                                                |
+//! | 28     | `u32`     | reserved                                                       |
+//! | 32     | `u64`     | size of a slot in bytes, including its header                  |
+//! | 40     | `u64`     | sequence number of the latest complete frame, `0` before any   |
+//! | 48     |           | reserved up to the end of the header                           |
+//!
+//! The pixel format is one of the [`PixelFormat`]s: `1` RGBA8, `2` L8, `3` L16 or `4` RGB8.
+//!
+//! It is followed by the slots, slot `i` starting at `header size + i * slot size`. Each slot
+//! starts with a header of [`SHM_SLOT_HEADER_SIZE`] bytes, followed by the pixels of the frame,
+//! tightly packed row by row:
+//!
+//! | Offset | Type  | Field                                                               |
+//! |--------|-------|---------------------------------------------------------------------|
+//! | 0      | `u64` | seqlock, odd while the slot is written                              |
+//! | 8      | `u64` | id of the frame, see [`FrameContext::frame`]                        |
+//! | 16     | `u64` | elapsed simulation time in nanoseconds, see [`FrameContext::elapsed`] |
+//! | 24     | `u64` | reserved                                                            |
+//!
+//! # Protocol
+//!
+//! The frames are numbered by a sequence number starting at 1, frame `n` is written into slot
+//! `n % slots`. The writer stores `2n - 1` in the seqlock of the slot, writes the frame, stores
+//! `2n` in the seqlock and finally `n` as the latest sequence number of the header.
+//!
+//! A reader loads the latest sequence number `n`, checks that the seqlock of its slot is `2n`,
+//! copies the frame and checks the seqlock again. If either check fails, the slot was overwritten
+//! in the meantime and the reader starts over. With the default of three slots the writer only
+//! touches the slot of the latest frame after two more frames, so readers rarely retry.
+//!
+//! The writer initializes the header when it is created, storing the magic last. Readers must
+//! check the magic and the version before anything else.
+//!
+//! # Manifest
+//!
+//! [`write_manifest`] lists the frame segments of an app in a JSON file, so that consumers can
+//! find them without knowing their names, sizes and formats in advance.
 
+use super::{Encoder, FrameContext, FrameFormat, FrameView, OutputFormat, PixelFormat, Result};
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+use serde_json::{json, Map, Value};
+use shared_memory::{Shmem, ShmemConf, ShmemError};
+use std::{
+    fs, io,
+    path::Path,
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The magic bytes at the start of a frame segment.
+pub const SHM_MAGIC: [u8; 4] = *b"BCFR";
+/// The version of the protocol, increased with every incompatible change of the layout.
+pub const SHM_VERSION: u32 = 1;
+/// The size of the header of a frame segment in bytes.
+pub const SHM_HEADER_SIZE: usize = 64;
+/// The size of the header of each slot in bytes.
+pub const SHM_SLOT_HEADER_SIZE: usize = 32;
+/// The default number of slots of a [`MyCustomEncoder`], see [`ShmEncoderConfig::slots`].
+pub const DEFAULT_SLOTS: usize = 3;
+
+pub use serde_json;
+
+/// A frame segment listed in the manifest, see [`write_manifest`].
+#[derive(Debug, Clone)]
+pub struct ManifestCamera {
+    /// The index of the camera.
+    pub index: usize,
+    /// The name of the camera.
+    pub name: String,
+    /// The name of the segment the frames are written to.
+    pub segment: String,
+    /// The width of the frames in pixels.
+    pub width: u32,
+    /// The height of the frames in pixels.
+    pub height: u32,
+    /// The configuration of the encoder writing the frames.
+    pub config: ShmEncoderConfig,
+}
+
+/// Writes a JSON manifest listing the frame segments of the given cameras. The entries of
+/// `extra`, e.g. describing other segments of the app, are added to the top level object.
+///
+/// The file is replaced atomically, so consumers never read a partial manifest.
+pub fn write_manifest(
+    path: impl AsRef<Path>,
+    cameras: &[ManifestCamera],
+    extra: impl IntoIterator<Item = (String, Value)>,
+) -> io::Result<()> {
+    let cameras = cameras
+        .iter()
+        .map(|camera| {
+            json!({
+                "index": camera.index,
+                "name": camera.name,
+                "segment": camera.segment,
+                "width": camera.width,
+                "height": camera.height,
+                "format": camera.config.format.pixel_format.name(),
+                "protocol_version": SHM_VERSION,
+                "slots": camera.config.slots,
+            })
+        })
+        .collect();
+
+    let mut manifest = Map::new();
+    manifest.insert("cameras".into(), Value::Array(cameras));
+    manifest.extend(extra);
+
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(&manifest)? + "\n")?;
+    fs::rename(&tmp, path)
+}
+
+// Offsets of the header fields
+const MAGIC: usize = 0;
+const VERSION: usize = 4;
+const HEADER_SIZE: usize = 8;
+const WIDTH: usize = 12;
+const HEIGHT: usize = 16;
+const FORMAT: usize = 20;
+const SLOT_COUNT: usize = 24;
+const SLOT_SIZE: usize = 32;
+const LATEST: usize = 40;
+
+// Offsets of the slot header fields
+const SEQLOCK: usize = 0;
+const FRAME: usize = 8;
+const TIMESTAMP: usize = 16;
+
+/// How often a reader tries to read a frame whose slot is being overwritten.
+const READ_ATTEMPTS: usize = 16;
+
+/// Creates a shared memory segment of the given size, or opens it if it exists already. Errors if
+/// an existing segment is smaller.
+pub(super) fn open_or_create_shm(name: &str, size: usize) -> Result<Shmem> {
+    let shmem = match ShmemConf::new().os_id(name).size(size).create() {
+        Ok(shmem) => shmem,
+        Err(ShmemError::MappingIdExists) => ShmemConf::new()
+            .os_id(name)
+            .open()
+            .map_err(|e| format!("Failed to open existing shared memory '{}': {}", name, e))?,
+        Err(e) => {
+            return Err(format!(
+                "Failed to create shared memory '{}', size {} bytes: {}",
+                name, size, e
+            )
+            .into())
+        }
+    };
+    if shmem.len() < size {
+        return Err(format!(
+            "Existing shared memory '{}' holds {} bytes, {} needed",
+            name,
+            shmem.len(),
+            size
+        )
+        .into());
+    }
+    Ok(shmem)
+}
+
+/// Returns the code of the given pixel format, as stored in the header.
+fn format_code(format: PixelFormat) -> u32 {
+    match format {
+        PixelFormat::Rgba8 => 1,
+        PixelFormat::L8 => 2,
+        PixelFormat::L16 => 3,
+        PixelFormat::Rgb8 => 4,
+    }
+}
+
+/// Returns the pixel format with the given code, as stored in the header.
+fn format_from_code(code: u32) -> Option<PixelFormat> {
+    match code {
+        1 => Some(PixelFormat::Rgba8),
+        2 => Some(PixelFormat::L8),
+        3 => Some(PixelFormat::L16),
+        4 => Some(PixelFormat::Rgb8),
+        _ => None,
+    }
+}
+
+/// Returns the size of a slot holding frames of the given size, padded to a multiple of 64 bytes
+/// so that every slot header is aligned.
+fn slot_size(frame_size: usize) -> usize {
+    (SHM_SLOT_HEADER_SIZE + frame_size).next_multiple_of(64)
+}
+
+/// # Safety
+/// `offset` has to be 4 byte aligned and within the mapping of `base`.
+pub(super) unsafe fn u32_at<'a>(base: *const u8, offset: usize) -> &'a AtomicU32 {
+    &*(base.add(offset) as *const AtomicU32)
+}
+
+/// # Safety
+/// `offset` has to be 8 byte aligned and within the mapping of `base`.
+pub(super) unsafe fn u64_at<'a>(base: *const u8, offset: usize) -> &'a AtomicU64 {
+    &*(base.add(offset) as *const AtomicU64)
+}
+
+/// An encoder that writes the frames into a shared memory segment, see the
+/// [module documentation](self) for the layout.
+///
+/// The frames are converted to the output format of the [`ShmEncoderConfig`], RGBA8 by default.
+/// Their size is fixed when the encoder is created.
 pub struct MyCustomEncoder {
+    name: String,
     shmem: shared_memory::Shmem,
+    width: u32,
+    height: u32,
+    format: OutputFormat,
+    slots: usize,
+    slot_size: usize,
     frame_size: usize,
+    /// The sequence number of the last frame written.
+    sequence: u64,
+    lockstep: Option<Lockstep>,
+    /// Scratch for frames converted before they are copied into a slot.
+    scratch: Vec<u8>,
+}
+
+/// The configuration of a [`MyCustomEncoder`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShmEncoderConfig {
+    /// The number of slots, [`DEFAULT_SLOTS`] by default. A single slot saves memory, but readers
+    /// have to retry whenever they race the writer.
+    pub slots: usize,
+    /// The format the frames are written in, RGBA8 by default. The luminance formats emulate
+    /// monochrome cameras and cut the size of the frames to a quarter or half.
+    pub format: OutputFormat,
+}
+
+impl Default for ShmEncoderConfig {
+    fn default() -> Self {
+        Self {
+            slots: DEFAULT_SLOTS,
+            format: OutputFormat::default(),
+        }
+    }
+}
+
+/// The acknowledgement segment of the lockstep mode, named `<name>_ack`. It holds two
+/// native endian `u64`s:
+///
+/// - `0`: the sequence number of the last frame written, the same as the latest sequence
+///   number of the frame segment
+/// - `8`: the sequence number of the last frame the consumer has acknowledged
+///
+/// The consumer waits for the written sequence number to change, reads the frame and then
+/// stores the sequence number in the acknowledged slot, see [`ShmFrameReader::acknowledge`].
+/// Both are reset to 0 when the encoder is created.
+struct Lockstep {
+    shmem: shared_memory::Shmem,
+    /// How long to wait for an acknowledgement.
+    timeout: Duration,
+    /// Set once the encoder stopped waiting for a consumer that never attached, so it is only
+    /// logged once.
+    consumer_missing: bool,
+}
+
+impl Lockstep {
+    fn slot(&self, index: usize) -> &AtomicU64 {
+        // The mapping is page aligned and 16 bytes long
+        unsafe { &*(self.shmem.as_ptr() as *const AtomicU64).add(index) }
+    }
+
+    /// Publishes the frame with the given sequence number and blocks until the consumer has
+    /// acknowledged it. Doesn't block while no consumer is attached, i.e. none has acknowledged
+    /// a frame yet and the first frame timed out.
+    fn publish(&mut self, seq: u64, name: &str) -> Result<()> {
+        self.slot(0).store(seq, Ordering::Release);
+
+        let attached = self.slot(1).load(Ordering::Acquire) > 0;
+        if !attached && self.consumer_missing {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        while self.slot(1).load(Ordering::Acquire) < seq {
+            if start.elapsed() >= self.timeout {
+                if attached {
+                    return Err(format!("The consumer did not acknowledge frame {}", seq).into());
+                }
+                warn!(
+                    "No consumer acknowledged the frames of '{}', not waiting",
+                    name
+                );
+                self.consumer_missing = true;
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_micros(50));
+        }
+        Ok(())
+    }
 }
 
 impl MyCustomEncoder {
-    /// Creates a new encoder that writes raw RGBA8 frames into shared memory.
-    /// If the shared memory exists, it opens the existing mapping.
+    /// Creates a new encoder that writes RGBA8 frames into [`DEFAULT_SLOTS`] slots of shared
+    /// memory. If the shared memory exists, it opens the existing mapping.
     pub fn new(name: &str, width: usize, height: usize) -> Self {
-        let channels = 4;  // RGBA8 format
-        let frame_size = width * height * channels;
+        Self::new_with_config(name, width, height, ShmEncoderConfig::default())
+    }
 
-        let shmem = match ShmemConf::new()
-            .os_id(name)
-            .size(frame_size)
-            .create()
-        {
-            Ok(mem) => mem,
-            Err(e) => {
-                if let shared_memory::ShmemError::MappingIdExists = e {
-                    ShmemConf::new()
-                        .os_id(name)
-                        .open()
-                        .unwrap_or_else(|e| panic!("Failed to open existing shared memory '{}': {}", name, e))
-                } else {
-                    panic!("Failed to create shared memory '{}', size {} bytes: {}", name, frame_size, e);
-                }
+    /// Creates a new encoder that writes frames into shared memory, with the number of slots and
+    /// the pixel format of the configuration. If the shared memory exists, it opens the existing
+    /// mapping.
+    pub fn new_with_config(
+        name: &str,
+        width: usize,
+        height: usize,
+        config: ShmEncoderConfig,
+    ) -> Self {
+        let slots = config.slots.max(1);
+        let format = config.format;
+        let frame_size = width * height * format.pixel_format.bytes_per_pixel();
+        let slot_size = slot_size(frame_size);
+        let size = SHM_HEADER_SIZE + slots * slot_size;
+
+        let shmem = open_or_create_shm(name, size).unwrap_or_else(|e| panic!("{}", e));
+
+        // Invalidate the header while it is written, the magic is stored last
+        let base = shmem.as_ptr();
+        unsafe {
+            u32_at(base, MAGIC).store(0, Ordering::Relaxed);
+            fence(Ordering::Release);
+            u32_at(base, VERSION).store(SHM_VERSION, Ordering::Relaxed);
+            u32_at(base, HEADER_SIZE).store(SHM_HEADER_SIZE as u32, Ordering::Relaxed);
+            u32_at(base, WIDTH).store(width as u32, Ordering::Relaxed);
+            u32_at(base, HEIGHT).store(height as u32, Ordering::Relaxed);
+            u32_at(base, FORMAT).store(format_code(format.pixel_format), Ordering::Relaxed);
+            u32_at(base, SLOT_COUNT).store(slots as u32, Ordering::Relaxed);
+            u64_at(base, SLOT_SIZE).store(slot_size as u64, Ordering::Relaxed);
+            u64_at(base, LATEST).store(0, Ordering::Relaxed);
+            for slot in 0..slots {
+                let slot = base.add(SHM_HEADER_SIZE + slot * slot_size);
+                u64_at(slot, SEQLOCK).store(0, Ordering::Relaxed);
             }
-        };
+            u32_at(base, MAGIC).store(u32::from_ne_bytes(SHM_MAGIC), Ordering::Release);
+        }
 
-        println!("buffer created for mem_encoder size {}", frame_size);
+        debug!(
+            "Created shared memory '{}' for frames, {} bytes",
+            name, size
+        );
 
-        Self { shmem, frame_size }
+        Self {
+            name: name.to_string(),
+            shmem,
+            width: width as u32,
+            height: height as u32,
+            format,
+            slots,
+            slot_size,
+            frame_size,
+            sequence: 0,
+            lockstep: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Runs in lockstep with the consumer: after writing a frame, the encoder blocks until the
+    /// consumer has acknowledged it through the `<name>_ack` segment, so no frame is overwritten
+    /// unseen. Errors if no acknowledgement arrives within `timeout`.
+    ///
+    /// If no consumer has acknowledged a frame yet, the encoder waits `timeout` once, logs a
+    /// warning and then writes the frames without waiting until a consumer acknowledges one.
+    ///
+    /// Together with [`CapturePlugin::offline`](crate::CapturePlugin::offline) the simulation
+    /// also waits for the consumer, rendering frame N+1 only after frame N was acknowledged. A
+    /// [`gate_timeout`](crate::OfflineMode::gate_timeout) shorter than `timeout` lets the
+    /// simulation continue without the frame.
+    pub fn with_lockstep(mut self, timeout: Duration) -> Self {
+        let name = format!("{}_ack", self.name);
+        let size = 2 * std::mem::size_of::<u64>();
+
+        let shmem = open_or_create_shm(&name, size).unwrap_or_else(|e| panic!("{}", e));
+
+        let lockstep = Lockstep {
+            shmem,
+            timeout,
+            consumer_missing: false,
+        };
+        lockstep.slot(0).store(0, Ordering::Release);
+        lockstep.slot(1).store(0, Ordering::Release);
+        self.lockstep = Some(lockstep);
+        self
     }
 }
 
@@ -54,40 +423,487 @@ impl MyCustomEncoder {
 //     }
 // }
 
-impl Encoder for MyCustomEncoder {
-    fn encode(&mut self, image: &Image) -> Result<()> {
-        // Convert Bevy's Image to DynamicImage
-        let dynamic_image = image.clone().try_into_dynamic()?;
+impl MyCustomEncoder {
+    fn write(&mut self, frame: &FrameView<'_>, id: u64, elapsed: Duration) -> Result<()> {
+        // Ensure the frame fits the slots
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err(format!(
+                "Frame size mismatch: expected {}x{}, got {}x{}",
+                self.width, self.height, frame.width, frame.height
+            )
+            .into());
+        }
 
-        // Convert to RGBA8 format and get raw bytes
-        let rgba_image = dynamic_image.to_rgba8();
-        let raw_data = rgba_image.as_raw();
+        let sequence = self.sequence + 1;
+        let slot_index = (sequence % self.slots as u64) as usize;
+        let offset = SHM_HEADER_SIZE + slot_index * self.slot_size;
+        let base = self.shmem.as_ptr();
+        let (seqlock, latest) = unsafe {
+            let slot = base.add(offset);
+            (u64_at(slot, SEQLOCK), u64_at(base, LATEST))
+        };
 
-        // Ensure data size is correct
-        if raw_data.len() != self.frame_size {
-            return Err(format!(
-                "Frame size mismatch: expected {}, got {}",
-                self.frame_size,
-                raw_data.len()
-            ).into());
+        // Mark the slot as being written
+        seqlock.store(2 * sequence - 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        unsafe {
+            let slot = base.add(offset);
+            u64_at(slot, FRAME).store(id, Ordering::Relaxed);
+            u64_at(slot, TIMESTAMP).store(elapsed.as_nanos() as u64, Ordering::Relaxed);
         }
-        // else{
-        //     println!("Frame size match OK: expected {}, got {}", self.frame_size,raw_data.len());
-        // }
 
         // Copy frame data into shared memory
-        unsafe {
-            let buffer = &mut self.shmem.as_slice_mut()[..self.frame_size];
-            buffer.copy_from_slice(raw_data);
+        let start = offset + SHM_SLOT_HEADER_SIZE;
+        let buffer = unsafe { &mut self.shmem.as_slice_mut()[start..start + self.frame_size] };
+        match frame.format {
+            // Straight from the staging buffer, row by row to drop the padding
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb
+                if self.format.pixel_format == PixelFormat::Rgba8 =>
+            {
+                for (dst, row) in buffer.chunks_exact_mut(frame.row_bytes()).zip(frame.rows()) {
+                    dst.copy_from_slice(row);
+                }
+            }
+            _ => buffer.copy_from_slice(frame.to_output(self.format, &mut self.scratch)?),
+        }
+
+        // Complete the slot and publish it as the latest frame
+        seqlock.store(2 * sequence, Ordering::Release);
+        latest.store(sequence, Ordering::Release);
+        self.sequence = sequence;
+
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.publish(sequence, &self.name)?;
         }
 
         Ok(())
     }
+}
+
+impl Encoder for MyCustomEncoder {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        // Without a context, the frames are identified by their sequence number
+        self.write(&FrameView::of(image)?, self.sequence + 1, Duration::ZERO)
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, context: &FrameContext) -> Result<()> {
+        self.write(frame, context.frame, context.elapsed)
+    }
+
+    fn format_changed(&mut self, _from: FrameFormat, to: FrameFormat) -> Result<()> {
+        // The slots are sized when the encoder is created, other formats are converted
+        if (to.width, to.height) != (self.width, self.height) {
+            return Err(format!(
+                "Frame size changed: shared memory holds {}x{} frames, got {}x{}",
+                self.width, self.height, to.width, to.height
+            )
+            .into());
+        }
+        Ok(())
+    }
 
-    fn finish(self: Box<Self>) {
+    fn finish(self: Box<Self>) -> Result<()> {
         // Shared memory will be cleaned up automatically
+        Ok(())
     }
 }
 
 unsafe impl Send for MyCustomEncoder {}
 unsafe impl Sync for MyCustomEncoder {}
+
+/// A frame read by a [`ShmFrameReader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmFrame {
+    /// The sequence number of the frame, starting at 1.
+    pub sequence: u64,
+    /// The id of the frame, see [`FrameContext::frame`].
+    pub frame: u64,
+    /// The elapsed simulation time of the frame, see [`FrameContext::elapsed`].
+    pub timestamp: Duration,
+    /// The width of the frame in pixels.
+    pub width: u32,
+    /// The height of the frame in pixels.
+    pub height: u32,
+    /// The pixel format of the frame.
+    pub format: PixelFormat,
+}
+
+/// Reads the frames written by a [`MyCustomEncoder`] into shared memory, usually in another
+/// process.
+///
+/// # Example
+/// ```ignore
+/// let mut reader = ShmFrameReader::open("cam0_frame")?;
+/// let mut pixels = Vec::new();
+/// loop {
+///     let frame = reader.wait_latest(&mut pixels, None)?;
+///     process(&frame, &pixels);
+///     reader.acknowledge(frame.sequence)?;
+/// }
+/// ```
+pub struct ShmFrameReader {
+    name: String,
+    shmem: shared_memory::Shmem,
+    /// The acknowledgement segment, opened with the first acknowledgement.
+    ack: Option<shared_memory::Shmem>,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    header_size: usize,
+    slots: usize,
+    slot_size: usize,
+    /// The sequence number of the last frame read.
+    last: u64,
+}
+
+impl ShmFrameReader {
+    /// Opens the shared memory segment with the given name. Errors if it does not exist or was
+    /// not written by a compatible [`MyCustomEncoder`].
+    pub fn open(name: &str) -> Result<Self> {
+        let shmem = ShmemConf::new()
+            .os_id(name)
+            .open()
+            .map_err(|e| format!("Failed to open shared memory '{}': {}", name, e))?;
+        if shmem.len() < SHM_HEADER_SIZE {
+            return Err(format!("Shared memory '{}' is too small for a header", name).into());
+        }
+
+        let base = shmem.as_ptr();
+        let field = |offset| unsafe { u32_at(base, offset).load(Ordering::Relaxed) };
+        if unsafe { u32_at(base, MAGIC).load(Ordering::Acquire) } != u32::from_ne_bytes(SHM_MAGIC) {
+            return Err(format!("Shared memory '{}' holds no frames", name).into());
+        }
+        if field(VERSION) != SHM_VERSION {
+            return Err(format!(
+                "Shared memory '{}' has version {}, expected {}",
+                name,
+                field(VERSION),
+                SHM_VERSION
+            )
+            .into());
+        }
+
+        let format = format_from_code(field(FORMAT)).ok_or_else(|| {
+            format!(
+                "Shared memory '{}' has unknown pixel format {}",
+                name,
+                field(FORMAT)
+            )
+        })?;
+        let reader = Self {
+            name: name.to_string(),
+            ack: None,
+            width: field(WIDTH),
+            height: field(HEIGHT),
+            format,
+            header_size: field(HEADER_SIZE) as usize,
+            slots: field(SLOT_COUNT) as usize,
+            slot_size: unsafe { u64_at(base, SLOT_SIZE).load(Ordering::Relaxed) } as usize,
+            last: 0,
+            shmem,
+        };
+
+        let size = reader.header_size + reader.slots * reader.slot_size;
+        if reader.slots == 0
+            || reader.slot_size < SHM_SLOT_HEADER_SIZE + reader.frame_size()
+            || reader.shmem.len() < size
+        {
+            return Err(format!("Shared memory '{}' has an invalid layout", name).into());
+        }
+        Ok(reader)
+    }
+
+    /// Returns the width of the frames in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the frames in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the pixel format of the frames.
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Returns the size of a frame in bytes.
+    pub fn frame_size(&self) -> usize {
+        self.width as usize * self.height as usize * self.format.bytes_per_pixel()
+    }
+
+    /// Returns the sequence number of the latest complete frame, `0` if none was written yet.
+    pub fn latest_sequence(&self) -> u64 {
+        unsafe { u64_at(self.shmem.as_ptr(), LATEST).load(Ordering::Acquire) }
+    }
+
+    /// Copies the latest complete frame into `pixels` if it is newer than the last frame read.
+    /// The pixels are tightly packed row by row.
+    ///
+    /// Returns `None` if there is no newer frame, or if its slot was being overwritten during a
+    /// few attempts to read it, e.g. because the writer died in the middle of a frame.
+    pub fn read_latest(&mut self, pixels: &mut Vec<u8>) -> Option<ShmFrame> {
+        let base = self.shmem.as_ptr();
+        let frame_size = self.frame_size();
+        pixels.resize(frame_size, 0);
+
+        for _ in 0..READ_ATTEMPTS {
+            let sequence = self.latest_sequence();
+            if sequence == 0 || sequence == self.last {
+                return None;
+            }
+
+            let slot_index = (sequence % self.slots as u64) as usize;
+            let offset = self.header_size + slot_index * self.slot_size;
+            let (seqlock, frame, timestamp) = unsafe {
+                let slot = base.add(offset);
+                (
+                    u64_at(slot, SEQLOCK),
+                    u64_at(slot, FRAME),
+                    u64_at(slot, TIMESTAMP),
+                )
+            };
+
+            // The slot is being overwritten by a newer frame
+            if seqlock.load(Ordering::Acquire) != 2 * sequence {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let id = frame.load(Ordering::Relaxed);
+            let timestamp = timestamp.load(Ordering::Relaxed);
+            let start = offset + SHM_SLOT_HEADER_SIZE;
+            pixels.copy_from_slice(unsafe { &self.shmem.as_slice()[start..start + frame_size] });
+
+            // Retry if the writer started on the slot during the copy
+            fence(Ordering::Acquire);
+            if seqlock.load(Ordering::Relaxed) != 2 * sequence {
+                continue;
+            }
+
+            self.last = sequence;
+            return Some(ShmFrame {
+                sequence,
+                frame: id,
+                timestamp: Duration::from_nanos(timestamp),
+                width: self.width,
+                height: self.height,
+                format: self.format,
+            });
+        }
+        None
+    }
+
+    /// Waits until a frame newer than the last frame read was written and copies it into
+    /// `pixels`, see [`read_latest`](Self::read_latest). Errors if no frame arrives within
+    /// `timeout`, `None` waits forever.
+    pub fn wait_latest(
+        &mut self,
+        pixels: &mut Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> Result<ShmFrame> {
+        let start = Instant::now();
+        loop {
+            if let Some(frame) = self.read_latest(pixels) {
+                return Ok(frame);
+            }
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Err(format!("No frame was written to '{}'", self.name).into());
+            }
+            std::thread::sleep(Duration::from_micros(50));
+        }
+    }
+
+    /// Acknowledges the frame with the given sequence number to a writer running in lockstep,
+    /// see [`MyCustomEncoder::with_lockstep`]. Errors if the writer does not run in lockstep.
+    pub fn acknowledge(&mut self, sequence: u64) -> Result<()> {
+        if self.ack.is_none() {
+            let name = format!("{}_ack", self.name);
+            let shmem = ShmemConf::new()
+                .os_id(&name)
+                .open()
+                .map_err(|e| format!("Failed to open shared memory '{}': {}", name, e))?;
+            if shmem.len() < 2 * std::mem::size_of::<u64>() {
+                return Err(format!("Shared memory '{}' is too small", name).into());
+            }
+            self.ack = Some(shmem);
+        }
+
+        let ack = self.ack.as_ref().unwrap();
+        unsafe { u64_at(ack.as_ptr(), 8).store(sequence, Ordering::Release) };
+        Ok(())
+    }
+}
+
+unsafe impl Send for ShmFrameReader {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{test_context, test_view};
+
+    #[test]
+    fn round_trip() {
+        let name = format!("bevy_capture_test_round_trip_{}", std::process::id());
+        let (width, height, stride) = (3, 2, 16);
+        let mut encoder = MyCustomEncoder::new(&name, width, height);
+        let mut reader = ShmFrameReader::open(&name).unwrap();
+
+        let header = unsafe { reader.shmem.as_slice() };
+        assert_eq!(header[MAGIC..MAGIC + 4], SHM_MAGIC);
+        let field =
+            |offset: usize| u32::from_ne_bytes(header[offset..offset + 4].try_into().unwrap());
+        assert_eq!(field(VERSION), SHM_VERSION);
+        assert_eq!(field(WIDTH), 3);
+        assert_eq!(field(HEIGHT), 2);
+        assert_eq!(field(FORMAT), format_code(PixelFormat::Rgba8));
+        assert_eq!(
+            (reader.width(), reader.height(), reader.format()),
+            (3, 2, PixelFormat::Rgba8)
+        );
+
+        let mut pixels = Vec::new();
+        assert!(reader.read_latest(&mut pixels).is_none());
+
+        // More frames than slots, each row padded to the stride
+        for n in 1..=5u8 {
+            let data: Vec<u8> = (0..stride * height)
+                .map(|i| {
+                    if i % stride < 12 {
+                        n * 16 + i as u8
+                    } else {
+                        0xff
+                    }
+                })
+                .collect();
+            let frame = test_view(width as u32, height as u32, &data);
+            encoder
+                .encode_view(&frame, &test_context(100 + n as u64))
+                .unwrap();
+
+            let shm_frame = reader.read_latest(&mut pixels).unwrap();
+            assert_eq!(shm_frame.sequence, n as u64);
+            assert_eq!(shm_frame.frame, 100 + n as u64);
+            assert_eq!(
+                shm_frame.timestamp,
+                Duration::from_millis((100 + n as u64) * 10)
+            );
+            let expected: Vec<u8> = data
+                .chunks(stride)
+                .flat_map(|row| &row[..12])
+                .copied()
+                .collect();
+            assert_eq!(pixels, expected);
+
+            // No new frame
+            assert!(reader.read_latest(&mut pixels).is_none());
+        }
+        assert_eq!(reader.latest_sequence(), 5);
+    }
+
+    #[test]
+    fn luminance_round_trip() {
+        let name = format!("bevy_capture_test_luminance_{}", std::process::id());
+        let config = ShmEncoderConfig {
+            slots: 1,
+            format: PixelFormat::L8.into(),
+        };
+        let mut encoder = MyCustomEncoder::new_with_config(&name, 2, 1, config);
+        let mut reader = ShmFrameReader::open(&name).unwrap();
+        assert_eq!(reader.format(), PixelFormat::L8);
+
+        let frame = test_view(2, 1, &[255, 255, 255, 255, 0, 0, 0, 255]);
+        encoder.encode_view(&frame, &test_context(7)).unwrap();
+
+        let mut pixels = Vec::new();
+        let shm_frame = reader.read_latest(&mut pixels).unwrap();
+        assert_eq!((shm_frame.sequence, shm_frame.frame), (1, 7));
+        assert_eq!(pixels, [255, 0]);
+    }
+
+    #[test]
+    fn lockstep() {
+        let name = format!("bevy_capture_test_lockstep_{}", std::process::id());
+        let timeout = Duration::from_millis(20);
+        let mut encoder = MyCustomEncoder::new(&name, 1, 1).with_lockstep(timeout);
+        let mut reader = ShmFrameReader::open(&name).unwrap();
+        let frame = test_view(1, 1, &[1, 2, 3, 4]);
+
+        // Without a consumer only the first frame waits
+        let start = Instant::now();
+        for n in 1..=10 {
+            encoder.encode_view(&frame, &test_context(n)).unwrap();
+        }
+        assert!(start.elapsed() < timeout * 5);
+
+        // Once attached, a consumer that stops acknowledging fails the frame
+        reader.acknowledge(10).unwrap();
+        encoder.encode_view(&frame, &test_context(11)).unwrap_err();
+    }
+
+    #[test]
+    fn manifest() {
+        let path = std::env::temp_dir().join(format!(
+            "bevy_capture_test_manifest_{}.json",
+            std::process::id()
+        ));
+        let camera = ManifestCamera {
+            index: 1,
+            name: "data_camera \"front\"".into(),
+            segment: "cam1_frame".into(),
+            width: 64,
+            height: 48,
+            config: ShmEncoderConfig {
+                format: PixelFormat::L8.into(),
+                ..default()
+            },
+        };
+        let lines = json!({ "segment": "lines", "max_lines": 16 });
+        write_manifest(&path, &[camera], [("lines".into(), lines.clone())]).unwrap();
+
+        let manifest: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            manifest,
+            json!({
+                "cameras": [{
+                    "index": 1,
+                    "name": "data_camera \"front\"",
+                    "segment": "cam1_frame",
+                    "width": 64,
+                    "height": 48,
+                    "format": "l8",
+                    "protocol_version": SHM_VERSION,
+                    "slots": DEFAULT_SLOTS,
+                }],
+                "lines": lines,
+            })
+        );
+    }
+
+    #[test]
+    fn torn_slot() {
+        let name = format!("bevy_capture_test_torn_slot_{}", std::process::id());
+        let config = ShmEncoderConfig {
+            slots: 1,
+            ..default()
+        };
+        let mut encoder = MyCustomEncoder::new_with_config(&name, 1, 1, config);
+        let mut reader = ShmFrameReader::open(&name).unwrap();
+        let frame = test_view(1, 1, &[1, 2, 3, 4]);
+        encoder.encode_view(&frame, &test_context(1)).unwrap();
+
+        // The writer died while writing the next frame into the only slot
+        let slot = unsafe { u64_at(encoder.shmem.as_ptr().add(SHM_HEADER_SIZE), SEQLOCK) };
+        slot.store(3, Ordering::Release);
+
+        let mut pixels = Vec::new();
+        assert!(reader.read_latest(&mut pixels).is_none());
+        assert!(reader
+            .wait_latest(&mut pixels, Some(Duration::from_millis(10)))
+            .is_err());
+    }
+}
@@ -12,8 +12,23 @@ pub mod mp4_openh264;
 #[cfg(feature = "mp4_ffmpeg_cli")]
 pub mod mp4_ffmpeg_cli;
 
+pub mod per_view;
 
-use bevy::prelude::*;
+pub mod segmented;
+
+pub mod blobs;
+
+
+use bevy::{
+    image::TextureFormatPixelInfo,
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+use image::{DynamicImage, Rgba32FImage};
+use std::time::Duration;
 
 /// An error that occurred during encoding.
 pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -21,12 +36,689 @@ pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 /// The result type for encoding operations.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Information about a captured frame, snapshotted when the frame was extracted.
+#[derive(Debug, Clone)]
+pub struct FrameContext {
+    /// The id of the frame. It increases monotonically and is the same for all captures that
+    /// were extracted in the same app update.
+    pub frame: u64,
+    /// The elapsed [`Time`] of the main world at extraction.
+    pub elapsed: Duration,
+    /// The camera entity the frame was captured from.
+    pub camera: Entity,
+    /// The global transform of the camera.
+    pub transform: GlobalTransform,
+    /// The projection matrix of the camera, i.e. clip from view.
+    pub projection: Mat4,
+    /// The physical viewport of the camera in pixels, if it could be computed.
+    pub viewport: Option<URect>,
+}
+
 /// An encoder that encodes a sequence of images into a custom format.
 pub trait Encoder {
     /// Encodes the given image.
     fn encode(&mut self, image: &Image) -> Result<()>;
 
+    /// Encodes the given image together with the context of the frame it was captured in.
+    /// Defaults to [`encode`](Encoder::encode), override this if the encoder needs the context.
+    fn encode_frame(&mut self, image: &Image, context: &FrameContext) -> Result<()> {
+        let _ = context;
+        self.encode(image)
+    }
+
+    /// Encodes a borrowed view of the frame, which is how captures deliver their frames.
+    /// Defaults to copying the view into an [`Image`] and calling
+    /// [`encode_frame`](Encoder::encode_frame), override this to consume the pixels without
+    /// copying them.
+    fn encode_view(&mut self, frame: &FrameView<'_>, context: &FrameContext) -> Result<()> {
+        self.encode_frame(&frame.to_image(), context)
+    }
+
+    /// Called before the first frame whose size or format differs from the previous frames,
+    /// e.g. because the target was resized during the capture.
+    ///
+    /// Return an error if the encoder can't handle the change. The encoder is then finished and
+    /// removed from the capture, instead of corrupting its output with the new frames.
+    fn format_changed(&mut self, from: FrameFormat, to: FrameFormat) -> Result<()> {
+        let _ = (from, to);
+        Ok(())
+    }
+
     /// Finishes the encoding process.
     /// This method can be used to finalize the encoding process and write any remaining data, if necessary.
-    fn finish(self: Box<Self>) {}
+    ///
+    /// Return an error if the output could not be finalized, e.g. a truncated video. Captures
+    /// report it with an [`EncodeFailed`](crate::events::EncodeFailed) event.
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The size and pixel format of captured frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The pixel format.
+    pub format: TextureFormat,
+}
+
+impl FrameFormat {
+    /// Returns the format of the given image.
+    pub fn of(image: &Image) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            format: image.texture_descriptor.format,
+        }
+    }
+}
+
+/// A pixel format encoders can write the frames in, see [`OutputFormat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelFormat {
+    /// 8 bit luminance.
+    L8,
+    /// 16 bit luminance, native endian.
+    L16,
+    /// 8 bit red, green and blue.
+    Rgb8,
+    /// 8 bit red, green, blue and alpha.
+    #[default]
+    Rgba8,
+}
+
+impl PixelFormat {
+    /// Returns the lowercase name of the format, e.g. `rgba8`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::L8 => "l8",
+            Self::L16 => "l16",
+            Self::Rgb8 => "rgb8",
+            Self::Rgba8 => "rgba8",
+        }
+    }
+
+    /// Returns the size of a pixel in bytes.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::L8 => 1,
+            Self::L16 => 2,
+            Self::Rgb8 => 3,
+            Self::Rgba8 => 4,
+        }
+    }
+
+    /// Returns the matching [`ColorType`](image::ColorType).
+    pub fn color_type(self) -> image::ColorType {
+        match self {
+            Self::L8 => image::ColorType::L8,
+            Self::L16 => image::ColorType::L16,
+            Self::Rgb8 => image::ColorType::Rgb8,
+            Self::Rgba8 => image::ColorType::Rgba8,
+        }
+    }
+}
+
+/// The weights of red, green and blue in the luminance of [`L8`](PixelFormat::L8) and
+/// [`L16`](PixelFormat::L16) frames.
+///
+/// They are applied to the stored values of the frame, i.e. to the gamma encoded values of sRGB
+/// frames. Defaults to [`REC_709`](Self::REC_709).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LumaWeights {
+    /// The weight of red.
+    pub r: f32,
+    /// The weight of green.
+    pub g: f32,
+    /// The weight of blue.
+    pub b: f32,
+}
+
+impl LumaWeights {
+    /// The weights of ITU-R BT.709, as used by sRGB.
+    pub const REC_709: Self = Self::new(0.2126, 0.7152, 0.0722);
+    /// The weights of ITU-R BT.601.
+    pub const REC_601: Self = Self::new(0.299, 0.587, 0.114);
+    /// Equal weights, the mean of the channels.
+    pub const AVERAGE: Self = Self::new(1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0);
+
+    /// Creates new weights. They should add up to `1`, larger sums saturate.
+    pub const fn new(r: f32, g: f32, b: f32) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Returns the luminance of the given color, with channels in `0..=1`.
+    pub fn luminance(&self, r: f32, g: f32, b: f32) -> f32 {
+        (self.r * r + self.g * g + self.b * b).clamp(0.0, 1.0)
+    }
+}
+
+impl Default for LumaWeights {
+    fn default() -> Self {
+        Self::REC_709
+    }
+}
+
+/// The format encoders write the frames in: a [`PixelFormat`] and, for the luminance formats, the
+/// [`LumaWeights`].
+///
+/// # Example
+/// ```ignore
+/// // Emulate a monochrome IR camera that mostly sees the red channel
+/// let format = OutputFormat::new(PixelFormat::L8).with_luma(LumaWeights::new(0.8, 0.15, 0.05));
+/// let encoder = FramesEncoder::new("captures/ir").with_output_format(format);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OutputFormat {
+    /// The pixel format.
+    pub pixel_format: PixelFormat,
+    /// The weights of the luminance formats.
+    pub luma: LumaWeights,
+}
+
+impl OutputFormat {
+    /// Creates a new output format with the default [`LumaWeights`].
+    pub fn new(pixel_format: PixelFormat) -> Self {
+        Self {
+            pixel_format,
+            luma: LumaWeights::default(),
+        }
+    }
+
+    /// Sets the weights of the luminance formats.
+    pub fn with_luma(mut self, luma: LumaWeights) -> Self {
+        self.luma = luma;
+        self
+    }
+}
+
+impl From<PixelFormat> for OutputFormat {
+    fn from(pixel_format: PixelFormat) -> Self {
+        Self::new(pixel_format)
+    }
+}
+
+/// A borrowed view of the pixels of a captured frame.
+///
+/// Captures pass their frames straight from the mapped staging buffer, whose rows are padded to
+/// the copy alignment of the gpu. Use [`row`](Self::row) or [`rows`](Self::rows) to skip the
+/// padding.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameView<'a> {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The number of bytes from the start of one row to the start of the next, at least
+    /// [`row_bytes`](Self::row_bytes).
+    pub stride: usize,
+    /// The pixel format.
+    pub format: TextureFormat,
+    /// The pixel data, `height` rows of `stride` bytes. The padding of the last row may be
+    /// missing.
+    pub data: &'a [u8],
+}
+
+impl<'a> FrameView<'a> {
+    /// Returns a view of the given image, whose rows are tightly packed. Fails if the image
+    /// has no data or less than its size requires.
+    pub fn of(image: &'a Image) -> Result<Self> {
+        let format = image.texture_descriptor.format;
+        let stride = image.width() as usize * format.pixel_size();
+        let data = image.data.as_deref().ok_or("The image has no data")?;
+        if data.len() < stride * image.height() as usize {
+            return Err("Image data does not match its size".into());
+        }
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            stride,
+            format,
+            data,
+        })
+    }
+
+    /// Returns the size and pixel format of the frame.
+    pub fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+        }
+    }
+
+    /// Returns the number of bytes of the pixels of a row, without padding.
+    pub fn row_bytes(&self) -> usize {
+        self.width as usize * self.format.pixel_size()
+    }
+
+    /// Returns `true` if the rows are not padded.
+    pub fn is_packed(&self) -> bool {
+        self.stride == self.row_bytes()
+    }
+
+    /// Returns the pixels of the row `y`, without padding.
+    pub fn row(&self, y: u32) -> &'a [u8] {
+        let start = y as usize * self.stride;
+        &self.data[start..start + self.row_bytes()]
+    }
+
+    /// Returns the pixels of the rows from top to bottom, without padding.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let frame = *self;
+        (0..frame.height).map(move |y| frame.row(y))
+    }
+
+    /// Copies the pixels into `out` without padding, reusing its allocation.
+    pub fn copy_packed_into(&self, out: &mut Vec<u8>) {
+        out.clear();
+        out.reserve(self.row_bytes() * self.height as usize);
+        for row in self.rows() {
+            out.extend_from_slice(row);
+        }
+    }
+
+    /// Returns the pixels as tightly packed RGBA8. They are borrowed from the frame if it
+    /// already has that layout, otherwise they are converted into `scratch`, reusing its
+    /// allocation. Float frames are clamped to `0..=1`.
+    pub fn to_rgba8<'b>(&'b self, scratch: &'b mut Vec<u8>) -> Result<&'b [u8]> {
+        match self.format {
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb if self.is_packed() => {
+                Ok(&self.data[..self.row_bytes() * self.height as usize])
+            }
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+                self.copy_packed_into(scratch);
+                Ok(scratch)
+            }
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+                scratch.clear();
+                scratch.reserve(self.row_bytes() * self.height as usize);
+                for row in self.rows() {
+                    for pixel in row.chunks_exact(4) {
+                        scratch.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+                    }
+                }
+                Ok(scratch)
+            }
+            _ => {
+                *scratch = self.to_dynamic_image()?.into_rgba8().into_raw();
+                Ok(scratch)
+            }
+        }
+    }
+
+    /// Returns the pixels as an owned buffer of tightly packed RGBA8, see
+    /// [`to_rgba8`](Self::to_rgba8). Converted frames are written into it directly.
+    pub fn to_rgba8_vec(&self) -> Result<Vec<u8>> {
+        match self.format {
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb if self.is_packed() => {
+                Ok(self.data[..self.row_bytes() * self.height as usize].to_vec())
+            }
+            _ => {
+                let mut pixels = Vec::new();
+                self.to_rgba8(&mut pixels)?;
+                Ok(pixels)
+            }
+        }
+    }
+
+    /// Returns the pixels as tightly packed pixels of the given output format. They are borrowed
+    /// from the frame if it already has that layout, otherwise they are converted into `scratch`,
+    /// reusing its allocation. Float frames are clamped to `0..=1`.
+    pub fn to_output<'b>(
+        &'b self,
+        format: OutputFormat,
+        scratch: &'b mut Vec<u8>,
+    ) -> Result<&'b [u8]> {
+        if format.pixel_format == PixelFormat::Rgba8 {
+            return self.to_rgba8(scratch);
+        }
+
+        let mut out = std::mem::take(scratch);
+        out.clear();
+        out.reserve(
+            self.width as usize * self.height as usize * format.pixel_format.bytes_per_pixel(),
+        );
+
+        // 8 bit color frames are converted without an intermediate image
+        let channels = match self.format {
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => Some([0, 1, 2]),
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => Some([2, 1, 0]),
+            _ => None,
+        };
+        match channels {
+            Some([r, g, b]) => {
+                for row in self.rows() {
+                    for pixel in row.chunks_exact(4) {
+                        push_pixel(
+                            &mut out,
+                            format,
+                            [pixel[r], pixel[g], pixel[b]].map(|c| c as f32 / 255.0),
+                        );
+                    }
+                }
+            }
+            None => {
+                let image = self.to_dynamic_image()?.into_rgba32f();
+                for pixel in image.pixels() {
+                    push_pixel(&mut out, format, [pixel[0], pixel[1], pixel[2]]);
+                }
+            }
+        }
+
+        *scratch = out;
+        Ok(scratch)
+    }
+
+    /// Copies the frame into a new [`Image`].
+    pub fn to_image(&self) -> Image {
+        let mut data = Vec::new();
+        self.copy_packed_into(&mut data);
+        Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            self.format,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    /// Copies the frame into a [`DynamicImage`], see [`to_dynamic_image`].
+    pub fn to_dynamic_image(&self) -> Result<DynamicImage> {
+        let image = self.to_image();
+        match self.format {
+            TextureFormat::Rgba16Float | TextureFormat::Rgba32Float => to_dynamic_image(&image),
+            _ => Ok(image.try_into_dynamic()?),
+        }
+    }
+}
+
+/// Appends a pixel with channels in `0..=1` in the given format, which is not RGBA8.
+fn push_pixel(out: &mut Vec<u8>, format: OutputFormat, [r, g, b]: [f32; 3]) {
+    let to_u8 = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    match format.pixel_format {
+        PixelFormat::L8 => out.push(to_u8(format.luma.luminance(r, g, b))),
+        PixelFormat::L16 => {
+            let luminance = (format.luma.luminance(r, g, b) * 65535.0).round() as u16;
+            out.extend_from_slice(&luminance.to_ne_bytes());
+        }
+        PixelFormat::Rgb8 => out.extend_from_slice(&[to_u8(r), to_u8(g), to_u8(b)]),
+        PixelFormat::Rgba8 => unreachable!("RGBA8 is converted by to_rgba8"),
+    }
+}
+
+/// The frames of all members of a [`CaptureGroup`](crate::CaptureGroup), captured in the same
+/// app update.
+#[derive(Debug)]
+pub struct FrameSet<'a> {
+    /// The id of the frame, shared by all views.
+    pub frame: u64,
+    /// The views in the order of the group members.
+    pub views: Vec<FrameSetView<'a>>,
+}
+
+/// A single view of a [`FrameSet`].
+#[derive(Debug)]
+pub struct FrameSetView<'a> {
+    /// The entity of the group member.
+    pub entity: Entity,
+    /// The captured frame.
+    pub frame: FrameView<'a>,
+    /// The context of the frame.
+    pub context: &'a FrameContext,
+}
+
+/// An encoder that encodes the frame sets of a [`CaptureGroup`](crate::CaptureGroup).
+///
+/// Use [`PerViewEncoder`](per_view::PerViewEncoder) to pass each view to its own [`Encoder`].
+pub trait GroupEncoder {
+    /// Encodes the given frame set.
+    fn encode_set(&mut self, frames: &FrameSet<'_>) -> Result<()>;
+
+    /// Called before the first frame set in which the size or format of the view with the
+    /// given index differs from the previous frame sets. See [`Encoder::format_changed`].
+    fn format_changed(&mut self, view: usize, from: FrameFormat, to: FrameFormat) -> Result<()> {
+        let _ = (view, from, to);
+        Ok(())
+    }
+
+    /// Finishes the encoding process. See [`Encoder::finish`].
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Converts a captured image into a [`DynamicImage`].
+///
+/// In addition to the formats supported by [`Image::try_into_dynamic`], float images
+/// ([`Rgba16Float`](TextureFormat::Rgba16Float) and [`Rgba32Float`](TextureFormat::Rgba32Float))
+/// are converted into an [`ImageRgba32F`](DynamicImage::ImageRgba32F) with their linear values
+/// intact. Converting those further into 8 bit clamps them to `0..=1`.
+pub fn to_dynamic_image(image: &Image) -> Result<DynamicImage> {
+    let data = image.data.as_deref().unwrap_or_default();
+    let pixels: Vec<f32> = match image.texture_descriptor.format {
+        TextureFormat::Rgba16Float => data
+            .chunks_exact(2)
+            .map(|bytes| f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])))
+            .collect(),
+        TextureFormat::Rgba32Float => data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect(),
+        _ => return Ok(image.clone().try_into_dynamic()?),
+    };
+
+    Rgba32FImage::from_raw(image.width(), image.height(), pixels)
+        .map(DynamicImage::ImageRgba32F)
+        .ok_or_else(|| "Image data does not match its size".into())
+}
+
+/// Converts the bits of an IEEE 754 half precision float.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits >> 15) as u32) << 31;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    let bits = match (exponent, mantissa) {
+        // Zero
+        (0, 0) => sign,
+        // Subnormal, normalize it
+        (0, _) => {
+            let shift = mantissa.leading_zeros() - 21;
+            sign | ((113 - shift) << 23) | (((mantissa << shift) & 0x3ff) << 13)
+        }
+        // Infinity or NaN
+        (0x1f, _) => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 112) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+/// Fails if the frames were resized, for encoders whose output has a fixed size. Other format
+/// changes are left to the conversion to the output format.
+#[cfg(any(feature = "gif", feature = "mp4_openh264", feature = "mp4_ffmpeg_cli"))]
+fn ensure_same_size(from: FrameFormat, to: FrameFormat, encoder: &str) -> Result<()> {
+    if (from.width, from.height) != (to.width, to.height) {
+        return Err(format!(
+            "The frames were resized from {}x{} to {}x{}, which {} can't encode",
+            from.width, from.height, to.width, to.height, encoder
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns an RGBA8 frame of the given size, whose stride is derived from the length of the
+/// pixels.
+#[cfg(test)]
+pub(crate) fn test_view(width: u32, height: u32, pixels: &[u8]) -> FrameView<'_> {
+    FrameView {
+        width,
+        height,
+        stride: pixels.len() / height as usize,
+        format: TextureFormat::Rgba8Unorm,
+        data: pixels,
+    }
+}
+
+/// Returns the context of the given frame, which was extracted after `frame * 10` ms.
+#[cfg(test)]
+pub(crate) fn test_context(frame: u64) -> FrameContext {
+    FrameContext {
+        frame,
+        elapsed: Duration::from_millis(frame * 10),
+        camera: Entity::PLACEHOLDER,
+        transform: GlobalTransform::IDENTITY,
+        projection: Mat4::IDENTITY,
+        viewport: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Red, green, blue and white pixels in a row, padded to a stride of 20 bytes.
+    const RGBA: [u8; 20] = [
+        255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 255, 255, 255, 7, 7, 7, 7,
+    ];
+
+    #[test]
+    fn to_output_l8() {
+        let frame = test_view(4, 1, &RGBA);
+        let mut scratch = Vec::new();
+
+        let l8 = frame
+            .to_output(PixelFormat::L8.into(), &mut scratch)
+            .unwrap();
+        assert_eq!(l8, [54, 182, 18, 255]);
+
+        let format = OutputFormat::new(PixelFormat::L8).with_luma(LumaWeights::REC_601);
+        assert_eq!(
+            frame.to_output(format, &mut scratch).unwrap(),
+            [76, 150, 29, 255]
+        );
+
+        let format = OutputFormat::new(PixelFormat::L8).with_luma(LumaWeights::AVERAGE);
+        assert_eq!(
+            frame.to_output(format, &mut scratch).unwrap(),
+            [85, 85, 85, 255]
+        );
+    }
+
+    #[test]
+    fn to_output_bgra() {
+        let frame = FrameView {
+            format: TextureFormat::Bgra8Unorm,
+            ..test_view(4, 1, &RGBA)
+        };
+        let mut scratch = Vec::new();
+
+        // The red and blue channels are swapped
+        let l8 = frame
+            .to_output(PixelFormat::L8.into(), &mut scratch)
+            .unwrap();
+        assert_eq!(l8, [18, 182, 54, 255]);
+        let rgb8 = frame
+            .to_output(PixelFormat::Rgb8.into(), &mut scratch)
+            .unwrap();
+        assert_eq!(rgb8, [0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255]);
+        assert_eq!(
+            frame.to_rgba8_vec().unwrap(),
+            [0, 0, 255, 255, 0, 255, 0, 255, 255, 0, 0, 255, 255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn to_output_l16() {
+        let frame = test_view(4, 1, &RGBA);
+        let mut scratch = Vec::new();
+
+        let l16: Vec<u16> = frame
+            .to_output(PixelFormat::L16.into(), &mut scratch)
+            .unwrap()
+            .chunks_exact(2)
+            .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+            .collect();
+        assert_eq!(l16, [13933, 46871, 4732, 65535]);
+    }
+
+    #[test]
+    fn to_output_float() {
+        // Half floats 0.5, 2.0 and -1.0, which are clamped
+        let pixels: [[u16; 4]; 3] = [
+            [0x3800, 0x3800, 0x3800, 0x3c00],
+            [0x4000, 0x4000, 0x4000, 0x3c00],
+            [0xbc00, 0xbc00, 0xbc00, 0x3c00],
+        ];
+        let data: Vec<u8> = pixels
+            .iter()
+            .flatten()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        let frame = FrameView {
+            format: TextureFormat::Rgba16Float,
+            ..test_view(3, 1, &data)
+        };
+        let mut scratch = Vec::new();
+
+        let l16: Vec<u16> = frame
+            .to_output(PixelFormat::L16.into(), &mut scratch)
+            .unwrap()
+            .chunks_exact(2)
+            .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+            .collect();
+        assert_eq!(l16, [32768, 65535, 0]);
+    }
+
+    #[test]
+    fn view_of_image() {
+        let size = Extent3d {
+            width: 4,
+            height: 1,
+            depth_or_array_layers: 1,
+        };
+        let image = Image::new(
+            size,
+            TextureDimension::D2,
+            RGBA[..16].to_vec(),
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::default(),
+        );
+        let frame = FrameView::of(&image).unwrap();
+        assert!(frame.is_packed());
+        assert_eq!(frame.row(0), &RGBA[..16]);
+
+        let uninit = Image::new_uninit(
+            size,
+            TextureDimension::D2,
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::default(),
+        );
+        assert!(FrameView::of(&uninit).is_err());
+    }
+
+    #[test]
+    fn f16() {
+        assert_eq!(f16_to_f32(0x0000), 0.0);
+        assert!(f16_to_f32(0x8000).is_sign_negative());
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x3555), 0.333_251_95);
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        // Subnormals
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x0200), 2f32.powi(-15));
+        assert_eq!(f16_to_f32(0x83ff), -1023.0 * 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+    }
 }
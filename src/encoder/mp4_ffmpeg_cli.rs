@@ -1,31 +1,78 @@
 //! MP4 encoder using ffmpeg CLI (ffmpeg must be in PATH).
 
-use super::{Encoder, Result};
+use super::{
+    ensure_same_size, Encoder, FrameContext, FrameFormat, FrameView, OutputFormat, PixelFormat,
+    Result,
+};
 use bevy::prelude::*;
-use std::{path::PathBuf, process::Command};
+use std::{
+    ffi::OsString,
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+    thread::JoinHandle,
+};
 use tempdir::TempDir;
 
+/// How the frames are passed to ffmpeg.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FfmpegInput {
+    /// Save every frame as a PNG into a temporary directory and run ffmpeg on them when the
+    /// encoder is finished. The video is only written at the end and the frames need disk space.
+    #[default]
+    TempPngs,
+    /// Start ffmpeg with the first frame and pipe the frames to it as raw video while they
+    /// arrive, so the video is written during the capture. If ffmpeg exits early, e.g. because of
+    /// an invalid argument, encoding the next frame fails with its exit status and error output.
+    /// Every further frame fails with the same error, ffmpeg is never restarted as that would
+    /// overwrite the video.
+    Pipe,
+}
+
 /// An encoder that encodes a sequence of images into an MP4 file using ffmpeg CLI.
 /// ffmpeg must be in PATH.
+///
+/// By default the frames are collected as PNGs and encoded when the encoder is finished, see
+/// [`FfmpegInput`] to pipe them to ffmpeg instead.
 pub struct Mp4FfmpegCliEncoder {
-    dir: TempDir,
-    frame: u32,
     path: PathBuf,
+    format: OutputFormat,
+    /// Scratch for converted frames.
+    buffer: Vec<u8>,
+    input: FfmpegInput,
+    /// The directory of the PNGs, for [`FfmpegInput::TempPngs`].
+    dir: Option<TempDir>,
+    frame: u32,
+    /// The running ffmpeg, for [`FfmpegInput::Pipe`].
+    ffmpeg: Option<Ffmpeg>,
+    /// The error ffmpeg failed with, returned for every further frame.
+    failed: Option<String>,
 
     framerate: u32,
-    crf: u32,
+    crf: Option<u32>,
+    codec: String,
+    pix_fmt: String,
+    args: Vec<OsString>,
 }
 
 impl Mp4FfmpegCliEncoder {
     /// Creates a new MP4 encoder that writes the MP4 to the given path.
     pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
         Ok(Self {
-            dir: TempDir::new("bevy_capture")?,
-            frame: 0,
             path: path.into(),
+            format: OutputFormat::default(),
+            buffer: Vec::new(),
+            input: FfmpegInput::default(),
+            dir: None,
+            frame: 0,
+            ffmpeg: None,
+            failed: None,
 
             framerate: 60,
-            crf: 23,
+            crf: Some(23),
+            codec: "libx264".to_string(),
+            pix_fmt: "yuv420p".to_string(),
+            args: Vec::new(),
         })
     }
 
@@ -35,52 +82,252 @@ impl Mp4FfmpegCliEncoder {
         self
     }
 
-    /// Sets the CRF (Constant Rate Factor) of the video.
-    pub fn with_crf(mut self, crf: u32) -> Self {
-        self.crf = crf;
+    /// Sets how the frames are passed to ffmpeg, [`FfmpegInput::TempPngs`] by default.
+    pub fn with_input(mut self, input: FfmpegInput) -> Self {
+        self.input = input;
         self
     }
-}
 
-impl Encoder for Mp4FfmpegCliEncoder {
-    fn encode(&mut self, image: &Image) -> Result<()> {
-        let image = image.clone().try_into_dynamic()?;
-        image.save(self.dir.path().join(format!("frame_{:06}.png", self.frame)))?;
+    /// Sets the format the frames are passed to ffmpeg in, RGBA8 by default. With
+    /// [`L8`](super::PixelFormat::L8) or [`L16`](super::PixelFormat::L16) the video holds the
+    /// luminance of the frames, computed with the given weights.
+    pub fn with_output_format(mut self, format: impl Into<OutputFormat>) -> Self {
+        self.format = format.into();
+        self
+    }
 
-        self.frame += 1;
+    /// Sets the CRF (Constant Rate Factor) of the video, `None` to leave it to the codec. Only
+    /// some codecs, like `libx264`, support it.
+    pub fn with_crf(mut self, crf: impl Into<Option<u32>>) -> Self {
+        self.crf = crf.into();
+        self
+    }
 
-        Ok(())
+    /// Sets the video codec, `libx264` by default. See `ffmpeg -encoders`.
+    pub fn with_codec(mut self, codec: impl Into<String>) -> Self {
+        self.codec = codec.into();
+        self
     }
 
-    fn finish(self: Box<Self>) {
-        let mut command;
-        if cfg!(target_os = "windows") {
-            command = Command::new("cmd");
-            command.arg("/C");
-        } else {
-            command = Command::new("sh");
-            command.arg("-c");
-        };
+    /// Sets the pixel format of the video, `yuv420p` by default. See `ffmpeg -pix_fmts`.
+    pub fn with_pix_fmt(mut self, pix_fmt: impl Into<String>) -> Self {
+        self.pix_fmt = pix_fmt.into();
+        self
+    }
 
-        command.arg("ffmpeg");
+    /// Adds extra arguments for the output, passed to ffmpeg right before the path.
+    ///
+    /// # Example
+    /// ```ignore
+    /// let encoder = Mp4FfmpegCliEncoder::new("capture.mp4")?
+    ///     .with_args(["-preset", "veryfast", "-tune", "zerolatency"]);
+    /// ```
+    pub fn with_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl Mp4FfmpegCliEncoder {
+    /// Returns the command that encodes the video from the given input.
+    fn command<I, S>(&self, input: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-hide_banner", "-loglevel", "error", "-y"]);
         command.arg("-framerate").arg(self.framerate.to_string());
+        command.args(input);
+        command.arg("-c:v").arg(&self.codec);
+        command.arg("-pix_fmt").arg(&self.pix_fmt);
+        if let Some(crf) = self.crf {
+            command.arg("-crf").arg(crf.to_string());
+        }
+        command.args(&self.args);
+        command.arg(&self.path);
         command
-            .arg("-i")
-            .arg(self.dir.path().join("frame_%06d.png"));
-        command.arg("-c:v").arg("libx264");
-        command.arg("-pix_fmt").arg("yuv420p");
-        command.arg("-crf").arg(self.crf.to_string());
-        command.arg(self.path);
-
-        match command.output() {
-            Ok(output) => {
-                if !output.status.success() {
-                    bevy::log::error!("ffmpeg failed: {:?}", output);
-                }
-            }
-            Err(error) => {
-                bevy::log::error!("ffmpeg failed: {:?}", error);
+    }
+
+    /// Returns the command that reads frames of the given size from stdin.
+    fn pipe_command(&self, width: u32, height: u32) -> Command {
+        let input_pix_fmt = match self.format.pixel_format {
+            PixelFormat::L8 => "gray",
+            PixelFormat::L16 if cfg!(target_endian = "big") => "gray16be",
+            PixelFormat::L16 => "gray16le",
+            PixelFormat::Rgb8 => "rgb24",
+            PixelFormat::Rgba8 => "rgba",
+        };
+        let size = format!("{}x{}", width, height);
+        self.command([
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            input_pix_fmt,
+            "-s",
+            &size,
+            "-i",
+            "-",
+        ])
+    }
+
+    fn write(&mut self, frame: &FrameView<'_>) -> Result<()> {
+        if let Some(err) = &self.failed {
+            return Err(err.clone().into());
+        }
+        match self.input {
+            FfmpegInput::TempPngs => self.save(frame),
+            FfmpegInput::Pipe => self.pipe(frame),
+        }
+    }
+
+    fn save(&mut self, frame: &FrameView<'_>) -> Result<()> {
+        let dir = match &mut self.dir {
+            Some(dir) => dir,
+            dir => dir.insert(TempDir::new("bevy_capture")?),
+        };
+
+        // Float frames are clamped to 8 bit, PNG can't store them
+        image::save_buffer(
+            dir.path().join(format!("frame_{:06}.png", self.frame)),
+            frame.to_output(self.format, &mut self.buffer)?,
+            frame.width,
+            frame.height,
+            self.format.pixel_format.color_type(),
+        )?;
+
+        self.frame += 1;
+
+        Ok(())
+    }
+
+    fn pipe(&mut self, frame: &FrameView<'_>) -> Result<()> {
+        if self.ffmpeg.is_none() {
+            let command = self.pipe_command(frame.width, frame.height);
+            match Ffmpeg::spawn(command) {
+                Ok(ffmpeg) => self.ffmpeg = Some(ffmpeg),
+                Err(err) => return Err(self.fail(err.to_string())),
             }
         }
+
+        // Float frames are clamped to 8 bit, or 16 bit for L16
+        let pixels = frame.to_output(self.format, &mut self.buffer)?;
+        let ffmpeg = self.ffmpeg.as_mut().unwrap();
+        if let Err(err) = ffmpeg.stdin.write_all(pixels) {
+            // ffmpeg exited, its status tells why
+            let err = match self.ffmpeg.take().unwrap().finish() {
+                Ok(()) => format!("Failed to pipe the frame to ffmpeg: {}", err),
+                Err(err) => err.to_string(),
+            };
+            return Err(self.fail(err));
+        }
+
+        Ok(())
+    }
+
+    /// Remembers the error, so the following frames fail with it instead of restarting ffmpeg.
+    fn fail(&mut self, err: String) -> super::Error {
+        self.failed = Some(err.clone());
+        err.into()
+    }
+
+    /// Encodes the PNGs of [`FfmpegInput::TempPngs`] into the video.
+    fn encode_pngs(&self, dir: &TempDir) -> Result<()> {
+        let output = self
+            .command([
+                OsString::from("-i"),
+                dir.path().join("frame_%06d.png").into(),
+            ])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|err| format!("Failed to start ffmpeg, is it in PATH? {}", err))?;
+        check_status(output.status, &String::from_utf8_lossy(&output.stderr))
+    }
+}
+
+impl Encoder for Mp4FfmpegCliEncoder {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        self.write(&FrameView::of(image)?)
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, _context: &FrameContext) -> Result<()> {
+        self.write(frame)
+    }
+
+    fn format_changed(&mut self, from: FrameFormat, to: FrameFormat) -> Result<()> {
+        // ffmpeg can't change the size of the video
+        ensure_same_size(from, to, "ffmpeg")
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        match (self.ffmpeg.take(), self.dir.take(), &self.failed) {
+            (Some(ffmpeg), _, _) => ffmpeg.finish(),
+            // The video is incomplete
+            (None, _, Some(err)) => Err(err.clone().into()),
+            (None, Some(dir), None) => self.encode_pngs(&dir),
+            (None, None, None) => Ok(()),
+        }
+    }
+}
+
+/// A running ffmpeg process reading raw video from stdin.
+struct Ffmpeg {
+    child: Child,
+    stdin: ChildStdin,
+    /// Collects the error output, so ffmpeg never blocks on a full pipe.
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl Ffmpeg {
+    fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("Failed to start ffmpeg, is it in PATH? {}", err))?;
+
+        let stdin = child.stdin.take().ok_or("ffmpeg has no stdin")?;
+        let stderr = child.stderr.take().map(|mut stderr| {
+            std::thread::spawn(move || {
+                let mut output = String::new();
+                let _ = stderr.read_to_string(&mut output);
+                output
+            })
+        });
+
+        Ok(Self {
+            child,
+            stdin,
+            stderr,
+        })
+    }
+
+    /// Closes stdin, waits for ffmpeg to exit and errors if it failed.
+    fn finish(self) -> Result<()> {
+        let Self {
+            mut child,
+            stdin,
+            stderr,
+        } = self;
+        drop(stdin);
+
+        let status = child.wait()?;
+        let stderr = stderr
+            .and_then(|stderr| stderr.join().ok())
+            .unwrap_or_default();
+        check_status(status, &stderr)
+    }
+}
+
+/// Errors with the error output if ffmpeg failed.
+fn check_status(status: ExitStatus, stderr: &str) -> Result<()> {
+    if !status.success() {
+        return Err(format!("ffmpeg failed with {}: {}", status, stderr.trim()).into());
     }
+    Ok(())
 }
@@ -1,8 +1,10 @@
 //! MP4 encoder using OpenH264.
 
-use super::{Encoder, Result};
+use super::{
+    ensure_same_size, Encoder, FrameContext, FrameFormat, FrameView, OutputFormat, PixelFormat,
+    Result,
+};
 use bevy::prelude::*;
-use image::RgbaImage;
 use mp4::{
     AvcConfig, FourCC, MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig, TrackType,
 };
@@ -28,6 +30,8 @@ pub struct Mp4Openh264Encoder<W> {
     frame: u64,
     width: u16,
     height: u16,
+    format: OutputFormat,
+    buffer: Vec<u8>,
 }
 
 impl<W: Write + Seek> Mp4Openh264Encoder<W> {
@@ -68,17 +72,30 @@ impl<W: Write + Seek> Mp4Openh264Encoder<W> {
             frame: 0,
             width,
             height,
+            format: OutputFormat::default(),
+            buffer: Vec::new(),
         })
     }
-}
 
-impl<W: Write + Seek> Encoder for Mp4Openh264Encoder<W> {
-    fn encode(&mut self, image: &Image) -> Result<()> {
-        let image = image.clone().try_into_dynamic()?;
-        let buffer = image.to_rgba8();
+    /// Sets the format the frames are converted to before encoding, RGBA8 by default. With
+    /// [`L8`](PixelFormat::L8) or [`L16`](PixelFormat::L16) the video holds the luminance of the
+    /// frames, computed with the given weights.
+    pub fn with_output_format(mut self, format: impl Into<OutputFormat>) -> Self {
+        self.format = format.into();
+        self
+    }
+}
 
+impl<W: Write + Seek> Mp4Openh264Encoder<W> {
+    fn encode_output(&mut self, frame: &FrameView<'_>) -> Result<()> {
+        let source = ImageSource {
+            pixels: frame.to_output(self.format, &mut self.buffer)?,
+            format: self.format.pixel_format,
+            width: frame.width as usize,
+            height: frame.height as usize,
+        };
         let bitstream = self.openh264.encode_at(
-            &YUVBuffer::from_rgb_source(ImageSource(buffer)),
+            &YUVBuffer::from_rgb_source(source),
             Timestamp::from_millis(self.frame * 100),
         )?;
 
@@ -124,24 +141,63 @@ impl<W: Write + Seek> Encoder for Mp4Openh264Encoder<W> {
         self.frame += 1;
         Ok(())
     }
+}
 
-    fn finish(mut self: Box<Self>) {
-        if let Err(err) = self.mp4.write_end() {
-            bevy::log::error!("Failed to write mp4 end: {}", err);
-        }
+impl<W: Write + Seek> Encoder for Mp4Openh264Encoder<W> {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        self.encode_output(&FrameView::of(image)?)
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, _context: &FrameContext) -> Result<()> {
+        self.encode_output(frame)
+    }
+
+    fn format_changed(&mut self, from: FrameFormat, to: FrameFormat) -> Result<()> {
+        // The size of the video track is fixed
+        ensure_same_size(from, to, "the mp4 encoder")
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.mp4
+            .write_end()
+            .map_err(|err| format!("Failed to write mp4 end: {}", err).into())
     }
 }
 
-struct ImageSource(RgbaImage);
+/// Tightly packed pixels of an output format.
+struct ImageSource<'a> {
+    pixels: &'a [u8],
+    format: PixelFormat,
+    width: usize,
+    height: usize,
+}
 
-impl RGBSource for ImageSource {
+impl RGBSource for ImageSource<'_> {
     fn dimensions(&self) -> (usize, usize) {
-        (self.0.width() as usize, self.0.height() as usize)
+        (self.width, self.height)
     }
 
     fn pixel_f32(&self, x: usize, y: usize) -> (f32, f32, f32) {
-        let [r, g, b, _] = self.0.get_pixel(x as u32, y as u32).0;
-        (r as f32, g as f32, b as f32)
+        let offset = (y * self.width + x) * self.format.bytes_per_pixel();
+        match self.format {
+            PixelFormat::L8 => {
+                let l = self.pixels[offset] as f32;
+                (l, l, l)
+            }
+            PixelFormat::L16 => {
+                let l = u16::from_ne_bytes([self.pixels[offset], self.pixels[offset + 1]]);
+                let l = l as f32 / 257.0;
+                (l, l, l)
+            }
+            PixelFormat::Rgb8 | PixelFormat::Rgba8 => {
+                let [r, g, b] = [
+                    self.pixels[offset],
+                    self.pixels[offset + 1],
+                    self.pixels[offset + 2],
+                ];
+                (r as f32, g as f32, b as f32)
+            }
+        }
     }
 }
 
@@ -0,0 +1,58 @@
+//! Passes each view of a frame set to its own encoder.
+
+use super::{FrameFormat, FrameSet, GroupEncoder, Result};
+use crate::{BoxedEncoder, IntoEncoders};
+
+/// A [`GroupEncoder`] that passes each view of a [`FrameSet`] to its own
+/// [`Encoder`](super::Encoder), e.g. to write each camera of a group into a separate file.
+///
+/// The encoders are matched to the views by index, i.e. in the order of the group members.
+pub struct PerViewEncoder {
+    encoders: Vec<BoxedEncoder>,
+}
+
+impl PerViewEncoder {
+    /// Creates a new encoder with one encoder per view.
+    pub fn new(encoders: impl IntoEncoders) -> Self {
+        Self {
+            encoders: encoders.into_encoders(),
+        }
+    }
+}
+
+impl GroupEncoder for PerViewEncoder {
+    fn encode_set(&mut self, frames: &FrameSet<'_>) -> Result<()> {
+        if frames.views.len() != self.encoders.len() {
+            return Err(format!(
+                "Expected {} views, got {}",
+                self.encoders.len(),
+                frames.views.len()
+            )
+            .into());
+        }
+
+        for (encoder, view) in self.encoders.iter_mut().zip(&frames.views) {
+            encoder.encode_view(&view.frame, view.context)?;
+        }
+        Ok(())
+    }
+
+    fn format_changed(&mut self, view: usize, from: FrameFormat, to: FrameFormat) -> Result<()> {
+        match self.encoders.get_mut(view) {
+            Some(encoder) => encoder.format_changed(from, to),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        // Finish all encoders, even if one of them fails
+        let mut result = Ok(());
+        for encoder in self.encoders {
+            let finished = encoder.finish();
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
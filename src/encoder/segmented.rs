@@ -0,0 +1,150 @@
+//! Splits a capture into segments, each written by its own encoder.
+
+use super::{Encoder, FrameContext, FrameFormat, FrameView, Result};
+use crate::BoxedEncoder;
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// The length of the segments of a [`SegmentedEncoder`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentLength {
+    /// A fixed number of frames.
+    Frames(u32),
+    /// A fixed span of the elapsed [`Time`] of the frames, see [`FrameContext::elapsed`].
+    Duration(Duration),
+}
+
+type CreateEncoder = Box<dyn FnMut(usize) -> Result<BoxedEncoder> + Send + Sync>;
+
+/// An encoder that splits the frames into segments and writes each of them with a new encoder,
+/// e.g. into `take_001.mp4`, `take_002.mp4` and so on.
+///
+/// The encoder of a segment is created for its first frame, with the number of the segment
+/// starting at `1`, and finished once the segment is full. A change of the frame size or format
+/// also starts a new segment.
+///
+/// # Example
+/// ```ignore
+/// let encoder = SegmentedEncoder::new(SegmentLength::Frames(300), |segment| {
+///     Mp4FfmpegCliEncoder::new(format!("take_{segment:03}.mp4"))
+/// });
+/// capture.start(encoder);
+/// ```
+pub struct SegmentedEncoder {
+    create: CreateEncoder,
+    length: SegmentLength,
+    /// The number of the last segment that was started.
+    segment: usize,
+    current: Option<Segment>,
+    /// Set when the format changed, the next frame starts a new segment.
+    split: bool,
+}
+
+struct Segment {
+    encoder: BoxedEncoder,
+    frames: u32,
+    /// The elapsed time of the first frame.
+    start: Duration,
+}
+
+impl SegmentedEncoder {
+    /// Creates a new segmented encoder that creates the encoder of each segment with the given
+    /// function from the number of the segment.
+    pub fn new<E>(
+        length: SegmentLength,
+        mut create: impl FnMut(usize) -> Result<E> + Send + Sync + 'static,
+    ) -> Self
+    where
+        E: Encoder + Send + Sync + 'static,
+    {
+        Self {
+            create: Box::new(move |segment| Ok(Box::new(create(segment)?))),
+            length,
+            segment: 0,
+            current: None,
+            split: false,
+        }
+    }
+
+    /// Returns the number of the current segment, `0` before the first frame.
+    pub fn segment(&self) -> usize {
+        self.segment
+    }
+
+    /// Finishes the current segment, the next frame starts a new one. Returns the error of the
+    /// encoder of the segment, if it could not be finished.
+    pub fn finish_segment(&mut self) -> Result<()> {
+        match self.current.take() {
+            Some(segment) => segment.encoder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Passes a frame to the encoder of the current segment, starting a new segment if it is
+    /// full. The elapsed time is only needed for segments of a duration.
+    ///
+    /// If the previous segment could not be finished, the frame is still encoded into the new
+    /// segment before the error is returned.
+    fn encode_segment(
+        &mut self,
+        elapsed: Option<Duration>,
+        encode: impl FnOnce(&mut BoxedEncoder) -> Result<()>,
+    ) -> Result<()> {
+        let full = match (&self.current, self.length) {
+            (None, _) => false,
+            (Some(_), _) if self.split => true,
+            (Some(segment), SegmentLength::Frames(frames)) => segment.frames >= frames.max(1),
+            (Some(segment), SegmentLength::Duration(duration)) => {
+                let elapsed = elapsed.ok_or("Segments of a duration need the frame context")?;
+                elapsed.saturating_sub(segment.start) >= duration
+            }
+        };
+        self.split = false;
+        let finished = if full { self.finish_segment() } else { Ok(()) };
+
+        let segment = match &mut self.current {
+            Some(segment) => segment,
+            None => {
+                self.segment += 1;
+                let encoder = (self.create)(self.segment)?;
+                self.current.insert(Segment {
+                    encoder,
+                    frames: 0,
+                    start: elapsed.unwrap_or_default(),
+                })
+            }
+        };
+        segment.frames += 1;
+        encode(&mut segment.encoder)?;
+        finished
+    }
+}
+
+impl Encoder for SegmentedEncoder {
+    fn encode(&mut self, image: &Image) -> Result<()> {
+        self.encode_segment(None, |encoder| encoder.encode(image))
+    }
+
+    fn encode_frame(&mut self, image: &Image, context: &FrameContext) -> Result<()> {
+        self.encode_segment(Some(context.elapsed), |encoder| {
+            encoder.encode_frame(image, context)
+        })
+    }
+
+    fn encode_view(&mut self, frame: &FrameView<'_>, context: &FrameContext) -> Result<()> {
+        self.encode_segment(Some(context.elapsed), |encoder| {
+            encoder.encode_view(frame, context)
+        })
+    }
+
+    fn format_changed(&mut self, _from: FrameFormat, _to: FrameFormat) -> Result<()> {
+        // Each segment has a single format, the next frame finishes the current segment and
+        // reports its errors
+        self.split = true;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.finish_segment()
+    }
+}
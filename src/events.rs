@@ -0,0 +1,104 @@
+//! Events about the lifecycle of captures.
+//!
+//! The events are sent from the render world and arrive in the main world with a delay of at
+//! least one frame, because frames are read back from the gpu asynchronously.
+
+use crate::{
+    encoder,
+    stats::{CaptureStats, FrameStats},
+};
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+
+/// Sent when a capture has received its encoders and starts capturing frames. Replacing the
+/// encoders of a running capture, e.g. with [`Capture::start`](crate::Capture::start), continues
+/// the capture and doesn't send it.
+#[derive(Debug, Clone, Event)]
+pub struct CaptureStarted {
+    /// The entity of the [`Capture`](crate::Capture) or [`CaptureGroup`](crate::CaptureGroup).
+    pub entity: Entity,
+}
+
+/// Sent after a frame has been passed to the encoders of a capture. Encoders that failed on the
+/// frame are reported with [`EncodeFailed`].
+#[derive(Debug, Clone, Event)]
+pub struct FrameCaptured {
+    /// The entity of the [`Capture`](crate::Capture) or [`CaptureGroup`](crate::CaptureGroup).
+    pub entity: Entity,
+    /// The id of the frame, see [`FrameContext::frame`](crate::encoder::FrameContext::frame).
+    pub frame: u64,
+}
+
+/// Sent when an encoder of a capture failed to encode a frame, or could not be finished once the
+/// capture stopped.
+#[derive(Debug, Event)]
+pub struct EncodeFailed {
+    /// The entity of the [`Capture`](crate::Capture) or [`CaptureGroup`](crate::CaptureGroup).
+    pub entity: Entity,
+    /// The error returned by the encoder.
+    pub error: encoder::Error,
+}
+
+/// Sent when a capture has been stopped and its encoders have been finished. Not sent for
+/// encoders that were replaced while capturing, see [`CaptureStarted`].
+#[derive(Debug, Clone, Event)]
+pub struct CaptureFinished {
+    /// The entity of the [`Capture`](crate::Capture) or [`CaptureGroup`](crate::CaptureGroup).
+    pub entity: Entity,
+}
+
+pub(crate) enum CaptureEvent {
+    Started(CaptureStarted),
+    FrameCaptured(FrameCaptured),
+    EncodeFailed(EncodeFailed),
+    Finished(CaptureFinished),
+    Stats(FrameStats),
+}
+
+/// The sending half of the event channel, in the render world.
+#[derive(Resource, Clone)]
+pub(crate) struct CaptureEventSender(Sender<CaptureEvent>);
+
+impl CaptureEventSender {
+    pub(crate) fn send(&self, event: CaptureEvent) {
+        // The receiver only disconnects when the app is shutting down
+        let _ = self.0.send(event);
+    }
+}
+
+/// The receiving half of the event channel, in the main world.
+#[derive(Resource)]
+pub(crate) struct CaptureEventReceiver(Receiver<CaptureEvent>);
+
+pub(crate) fn channel() -> (CaptureEventSender, CaptureEventReceiver) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    (CaptureEventSender(sender), CaptureEventReceiver(receiver))
+}
+
+pub(crate) fn receive_capture_events(
+    receiver: Res<CaptureEventReceiver>,
+    mut started: EventWriter<CaptureStarted>,
+    mut frame_captured: EventWriter<FrameCaptured>,
+    mut encode_failed: EventWriter<EncodeFailed>,
+    mut finished: EventWriter<CaptureFinished>,
+    mut stats: ResMut<CaptureStats>,
+) {
+    for event in receiver.0.try_iter() {
+        match event {
+            CaptureEvent::Started(event) => {
+                stats.reset(event.entity);
+                started.write(event);
+            }
+            CaptureEvent::FrameCaptured(event) => {
+                frame_captured.write(event);
+            }
+            CaptureEvent::EncodeFailed(event) => {
+                encode_failed.write(event);
+            }
+            CaptureEvent::Finished(event) => {
+                finished.write(event);
+            }
+            CaptureEvent::Stats(frame_stats) => stats.record(frame_stats),
+        }
+    }
+}
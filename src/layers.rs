@@ -0,0 +1,84 @@
+//! Keeps debug visuals out of the captured frames.
+//!
+//! Capture cameras only render the default render layer `0`, where the scene lives. Debug
+//! visuals tagged with [`DebugVisual`] are on [`DEBUG_RENDER_LAYER`], which all other cameras,
+//! i.e. the viewers, render as well. Set
+//! [`CapturePlugin::debug_visuals_in_captures`](crate::CapturePlugin::debug_visuals_in_captures)
+//! to include them in the captures.
+//!
+//! The layers are only assigned to cameras without [`RenderLayers`], cameras with their own
+//! layers are left alone. Gizmos are configured per gizmo group:
+//!
+//! ```ignore
+//! fn setup(mut config_store: ResMut<GizmoConfigStore>) {
+//!     let (config, _) = config_store.config_mut::<DefaultGizmoConfigGroup>();
+//!     config.render_layers = RenderLayers::layer(DEBUG_RENDER_LAYER);
+//! }
+//! ```
+
+use crate::{Capture, CaptureGroup, CaptureSource};
+use bevy::{ecs::entity::EntityHashSet, prelude::*, render::view::RenderLayers};
+
+/// The render layer of debug visuals.
+pub const DEBUG_RENDER_LAYER: usize = 31;
+
+/// Marks an entity as a debug visual, e.g. a helper mesh. It is put on the
+/// [`DEBUG_RENDER_LAYER`], so it only shows up in the viewers and not in the captures.
+#[derive(Debug, Default, Clone, Copy, Component)]
+#[require(RenderLayers = RenderLayers::layer(DEBUG_RENDER_LAYER))]
+pub struct DebugVisual;
+
+/// Whether capture cameras render debug visuals as well.
+#[derive(Resource)]
+pub(crate) struct DebugVisualsInCaptures(pub(crate) bool);
+
+/// Marks cameras whose render layers are assigned by [`assign_render_layers`].
+#[derive(Component)]
+pub(crate) struct AutoRenderLayers;
+
+/// Assigns the render layers of cameras, depending on whether they are capture cameras. They
+/// are updated when a camera becomes or stops being a capture camera.
+#[allow(clippy::type_complexity)]
+pub(crate) fn assign_render_layers(
+    mut commands: Commands,
+    cameras: Query<(Entity, Option<&RenderLayers>, Has<AutoRenderLayers>), With<Camera>>,
+    captures: Query<(Entity, &CaptureSource), With<Capture>>,
+    groups: Query<&CaptureGroup>,
+    sources: Query<&CaptureSource>,
+    debug_visuals_in_captures: Res<DebugVisualsInCaptures>,
+) {
+    let group_members = groups.iter().flat_map(|group| {
+        group.members().iter().map(|&member| {
+            let source = sources.get(member).cloned().unwrap_or_default();
+            (member, source)
+        })
+    });
+    let capture_cameras: EntityHashSet = captures
+        .iter()
+        .map(|(entity, source)| (entity, source.clone()))
+        .chain(group_members)
+        .filter_map(|(entity, source)| match source {
+            CaptureSource::ThisCamera => Some(entity),
+            CaptureSource::Camera(camera) => Some(camera),
+            // Window captures show what the viewer sees
+            _ => None,
+        })
+        .collect();
+
+    for (camera, layers, auto) in &cameras {
+        if layers.is_some() && !auto {
+            continue;
+        }
+
+        let render_layers = if capture_cameras.contains(&camera) && !debug_visuals_in_captures.0 {
+            RenderLayers::layer(0)
+        } else {
+            RenderLayers::layer(0).with(DEBUG_RENDER_LAYER)
+        };
+        if layers != Some(&render_layers) {
+            commands
+                .entity(camera)
+                .insert((render_layers, AutoRenderLayers));
+        }
+    }
+}
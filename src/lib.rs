@@ -3,35 +3,168 @@
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
 
+mod offline;
 mod render_world;
 
 pub mod encoder;
 
 pub mod animation;
 
+pub mod events;
+
+pub mod layers;
+
+pub mod stats;
+
 use bevy::{
-    image::BevyDefault,
+    image::{BevyDefault, TextureFormatPixelInfo},
     prelude::*,
     render::{
         camera::RenderTarget,
         render_asset::RenderAssetUsages,
         render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
+        view::VisibilitySystems,
+        RenderApp,
     },
+    time::TimeUpdateStrategy,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
-use std::sync::Mutex;
 use variadics_please::all_tuples;
 
 #[doc(inline)]
-pub use encoder::Encoder;
+pub use encoder::{Encoder, GroupEncoder};
 
 type BoxedEncoder = Box<dyn Encoder + Send + Sync + 'static>;
 
+type BoxedGroupEncoder = Box<dyn GroupEncoder + Send + Sync + 'static>;
+
 /// A Bevy plugin for capturing frames.
-pub struct CapturePlugin;
+#[derive(Default)]
+pub struct CapturePlugin {
+    /// Renders deterministically with a fixed timestep, `None` captures in real time.
+    pub offline: Option<OfflineMode>,
+    /// Whether capture cameras also render [debug visuals](layers::DebugVisual). By default
+    /// they only show up in the viewers.
+    pub debug_visuals_in_captures: bool,
+}
+
+impl CapturePlugin {
+    /// Creates a plugin that renders deterministically at the given frame rate, see
+    /// [`OfflineMode`].
+    pub fn offline(fps: f64) -> Self {
+        Self {
+            offline: Some(OfflineMode::new(fps)),
+            ..default()
+        }
+    }
+}
 
 impl Plugin for CapturePlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(render_world::CaptureRenderWorldPlugin);
+        let (sender, receiver) = events::channel();
+
+        app.add_event::<events::CaptureStarted>()
+            .add_event::<events::FrameCaptured>()
+            .add_event::<events::EncodeFailed>()
+            .add_event::<events::CaptureFinished>()
+            .init_resource::<stats::CaptureStats>()
+            .insert_resource(receiver)
+            .insert_resource(layers::DebugVisualsInCaptures(
+                self.debug_visuals_in_captures,
+            ))
+            .add_systems(
+                PreUpdate,
+                (events::receive_capture_events, stats::update_dropped_frames).chain(),
+            )
+            .add_systems(
+                PostUpdate,
+                layers::assign_render_layers.before(VisibilitySystems::CheckVisibility),
+            )
+            .add_plugins(render_world::CaptureRenderWorldPlugin);
+
+        app.sub_app_mut(RenderApp).insert_resource(sender);
+
+        if let Some(offline) = self.offline {
+            let gate = offline::FrameGate::new(offline.gate_timeout);
+            app.insert_resource(TimeUpdateStrategy::ManualDuration(offline.frame_time()))
+                .insert_resource(gate.clone())
+                .add_systems(First, offline::wait_for_captured_frames);
+            app.sub_app_mut(RenderApp).insert_resource(gate);
+        }
+    }
+
+    fn finish(&self, app: &mut App) {
+        // Don't clamp frame times of low frame rates
+        if let Some(offline) = self.offline {
+            if let Some(mut time) = app.world_mut().get_resource_mut::<Time<Virtual>>() {
+                let max_delta = time.max_delta().max(offline.frame_time());
+                time.set_max_delta(max_delta);
+            }
+        }
+    }
+}
+
+/// Deterministic offline rendering, e.g. for reproducible datasets.
+///
+/// [`Time`] advances by exactly `1 / fps` every app update, independent of the wall clock. Every
+/// captured frame is delivered to the encoders, and encoded if they run on an [`EncodeQueue`],
+/// before the next update starts. As long as the app itself is deterministic, runs produce
+/// identical frame sequences.
+///
+/// Frames are skipped while the pipelines of a capture are compiling, so also enable
+/// [`synchronous_pipeline_compilation`](bevy::render::RenderPlugin::synchronous_pipeline_compilation).
+/// The app runs as fast as it can render and encode, which may be slower or faster than real
+/// time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OfflineMode {
+    /// The frame rate, i.e. updates per second of simulated time. Must be positive and finite.
+    pub fps: f64,
+    /// How long an update waits for the frames of the previous one, before it continues
+    /// without them and logs a warning. `None`, the default, waits forever, so no frame is
+    /// ever lost.
+    pub gate_timeout: Option<Duration>,
+}
+
+impl OfflineMode {
+    /// Creates an offline mode with the given frame rate.
+    ///
+    /// # Panics
+    /// If `fps` is not positive and finite.
+    pub fn new(fps: f64) -> Self {
+        let mode = Self {
+            fps,
+            gate_timeout: None,
+        };
+        // Fail here rather than while the plugin is built
+        mode.frame_time();
+        mode
+    }
+
+    /// Sets how long an update waits for the frames of the previous one, see
+    /// [`gate_timeout`](Self::gate_timeout).
+    pub fn with_gate_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.gate_timeout = timeout.into();
+        self
+    }
+
+    /// Returns the time between two frames.
+    ///
+    /// # Panics
+    /// If `fps` is not positive and finite.
+    pub fn frame_time(&self) -> Duration {
+        match Duration::try_from_secs_f64(1.0 / self.fps) {
+            Ok(frame_time) if self.fps.is_finite() => frame_time,
+            _ => panic!(
+                "The fps of the OfflineMode must be positive and finite, got {}",
+                self.fps
+            ),
+        }
     }
 }
 
@@ -48,14 +181,166 @@ pub struct CaptureBundle {
 #[derive(Default, Component)]
 pub struct Capture {
     state: CaptureState,
+    capture_rate: CaptureRate,
+    region: CaptureRegion,
+    error_policy: ErrorPolicy,
+    encode_queue: Option<EncodeQueue>,
+    /// Shared with the render world, counts the frames dropped by the encode queue or because
+    /// all staging buffers were in flight.
+    dropped_frames: Arc<AtomicU64>,
 }
 
 impl Capture {
+    /// Sets the [`CaptureRate`] of the capture.
+    pub fn with_capture_rate(mut self, capture_rate: CaptureRate) -> Self {
+        self.capture_rate = capture_rate;
+        self
+    }
+
+    /// Sets the [`CaptureRate`] of the capture. Takes effect with the next frame.
+    pub fn set_capture_rate(&mut self, capture_rate: CaptureRate) {
+        self.capture_rate = capture_rate;
+    }
+
+    /// Returns the [`CaptureRate`] of the capture.
+    pub fn capture_rate(&self) -> CaptureRate {
+        self.capture_rate
+    }
+
+    /// Crops the frames to the given rectangle in physical pixels of the source.
+    pub fn with_crop(mut self, crop: URect) -> Self {
+        self.region.crop = Some(crop);
+        self
+    }
+
+    /// Scales the (cropped) frames by the given factor, e.g. `0.5` for half resolution.
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.region.scale = scale;
+        self
+    }
+
+    /// Sets the [`CaptureRegion`] of the capture. Takes effect with the next frame.
+    pub fn set_region(&mut self, region: CaptureRegion) {
+        self.region = region;
+    }
+
+    /// Returns the [`CaptureRegion`] of the capture.
+    pub fn region(&self) -> CaptureRegion {
+        self.region
+    }
+
+    /// Sets the [`ErrorPolicy`] of the capture.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Sets the [`ErrorPolicy`] of the capture. Takes effect with the next frame.
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    /// Returns the [`ErrorPolicy`] of the capture.
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    /// Runs the encoders on a dedicated worker thread, fed by the given queue, instead of in the
+    /// render schedule. Takes effect when the capture is started.
+    pub fn with_encode_queue(mut self, encode_queue: EncodeQueue) -> Self {
+        self.encode_queue = Some(encode_queue);
+        self
+    }
+
+    /// Sets the [`EncodeQueue`] of the capture, `None` encodes in the render schedule. Takes
+    /// effect when the capture is started.
+    pub fn set_encode_queue(&mut self, encode_queue: Option<EncodeQueue>) {
+        self.encode_queue = encode_queue;
+    }
+
+    /// Returns the [`EncodeQueue`] of the capture, if any.
+    pub fn encode_queue(&self) -> Option<EncodeQueue> {
+        self.encode_queue
+    }
+
+    /// Returns the number of frames that were dropped because the encode queue was full or
+    /// because all staging buffers were still in flight, i.e. the readback fell behind.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
     /// Starts capturing frames with the given encoders.
+    ///
+    /// If the capture is already capturing, the encoders are replaced without interrupting it.
+    /// The current encoders still receive the frames in flight and are then finished, the new
+    /// ones start with the next frame. See [`SegmentedEncoder`](encoder::segmented::SegmentedEncoder)
+    /// to roll over to new outputs automatically.
     pub fn start(&mut self, encoders: impl IntoEncoders) {
+        self.start_capturing(encoders, None, None);
+    }
+
+    /// Starts capturing frames with the given encoders, and the depth buffer of the camera with
+    /// the given depth encoders.
+    ///
+    /// The depth is delivered as an [`R32Float`](TextureFormat::R32Float) image of the linear
+    /// view space depth in meters. Pixels without any geometry are `f32::INFINITY`. The camera
+    /// must have a [`DepthPrepass`](bevy::core_pipeline::prepass::DepthPrepass), otherwise no
+    /// depth frames are captured.
+    ///
+    /// Replaces the encoders if the capture is already capturing, like [`start`](Self::start).
+    pub fn start_with_depth(
+        &mut self,
+        encoders: impl IntoEncoders,
+        depth_encoders: impl IntoEncoders,
+    ) {
+        self.start_capturing(
+            encoders,
+            Some(Encoders(depth_encoders.into_encoders())),
+            None,
+        );
+    }
+
+    /// Captures the next `frames` frames with the given encoders and then stops, see
+    /// [`is_complete`](Self::is_complete). Frames that are skipped, e.g. because of the
+    /// [`CaptureRate`] or while paused, don't count, so exactly `frames` frames are delivered to
+    /// the encoders. With `0` frames the capture is complete right away and the encoders are
+    /// finished without a frame.
+    ///
+    /// Replaces the encoders if the capture is already capturing, like [`start`](Self::start).
+    pub fn capture_frames(&mut self, frames: u64, encoders: impl IntoEncoders) {
+        self.start_capturing(encoders, None, Some(frames));
+        if frames == 0 {
+            if let CaptureState::Capturing {
+                encoders,
+                completed,
+                ..
+            } = &mut self.state
+            {
+                drop(encoders.get_mut().unwrap().take());
+                completed.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Captures the next frame with the given encoder and then stops, like
+    /// [`capture_frames`](Self::capture_frames) with a single frame.
+    pub fn capture_single(&mut self, encoder: impl IntoEncoders) {
+        self.capture_frames(1, encoder);
+    }
+
+    fn start_capturing(
+        &mut self,
+        encoders: impl IntoEncoders,
+        depth_encoders: Option<Encoders>,
+        frame_limit: Option<u64>,
+    ) {
         self.state = CaptureState::Capturing {
             encoders: Mutex::new(Some(Encoders(encoders.into_encoders()))),
+            depth_encoders: Mutex::new(depth_encoders),
             paused: false,
+            frame_limit,
+            stopped: Default::default(),
+            completed: Default::default(),
         };
     }
 
@@ -79,24 +364,127 @@ impl Capture {
         self.state = CaptureState::Idle;
     }
 
-    /// Returns `true` if the capture is currently capturing frames.
+    /// Returns `true` if the capture is currently capturing frames. This is `false` after the
+    /// capture has been stopped by its [`ErrorPolicy`] or is complete.
     pub fn is_capturing(&self) -> bool {
-        matches!(&self.state, CaptureState::Capturing { .. })
+        matches!(&self.state, CaptureState::Capturing { stopped, completed, .. }
+            if !stopped.load(Ordering::Acquire) && !completed.load(Ordering::Acquire))
+    }
+
+    /// Returns `true` once all frames requested with [`capture_frames`](Self::capture_frames)
+    /// or [`capture_single`](Self::capture_single) have been delivered to the encoders. The
+    /// encoders are finished afterwards and [`CaptureFinished`](events::CaptureFinished) is sent.
+    pub fn is_complete(&self) -> bool {
+        matches!(&self.state, CaptureState::Capturing { completed, .. } if completed.load(Ordering::Acquire))
     }
 
     /// Returns `true` if the capture is currently paused.
     pub fn is_paused(&self) -> bool {
-        matches!(&self.state, CaptureState::Capturing { paused: true, .. })
+        self.is_capturing() && matches!(&self.state, CaptureState::Capturing { paused: true, .. })
     }
 }
 
+/// Determines which frames of a [`Capture`] are captured. Skipped frames are neither copied
+/// nor read back.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CaptureRate {
+    /// Capture every frame.
+    #[default]
+    EveryFrame,
+    /// Capture every nth frame, starting with the first one.
+    EveryNthFrame(u32),
+    /// Capture frames at the given rate in hertz, based on the elapsed [`Time`]. A frame is
+    /// captured as soon as a sample is due, so the rate can't exceed the frame rate.
+    Hz(f64),
+}
+
+/// The region of the source that is captured. Cropping and scaling happen on the gpu, so the
+/// encoders receive the reduced image and only that is read back.
+///
+/// Scaling renders from the source, so a source image needs
+/// [`TextureUsages::TEXTURE_BINDING`], which [`CameraTargetHeadless`] sets. Depth frames are not
+/// affected and always have the size of the source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureRegion {
+    /// The rectangle to crop in physical pixels of the source, `None` captures the whole
+    /// source. It is clamped to the source.
+    pub crop: Option<URect>,
+    /// The factor the cropped frames are scaled by. Downscaling averages the covered pixels.
+    pub scale: f32,
+}
+
+impl Default for CaptureRegion {
+    fn default() -> Self {
+        Self {
+            crop: None,
+            scale: 1.0,
+        }
+    }
+}
+
+/// Determines what happens when an encoder of a [`Capture`] fails to encode a frame. Failures
+/// are always reported with [`EncodeFailed`](events::EncodeFailed).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Log the error and keep passing frames to the encoder.
+    #[default]
+    Log,
+    /// Finish and remove the failing encoder, the remaining encoders keep capturing.
+    DropEncoder,
+    /// Stop the whole capture after the given number of consecutive frames failed to encode.
+    /// Frames that encode successfully never stop the capture, `StopAfter(0)` behaves like
+    /// `StopAfter(1)` and stops on the first failed frame.
+    StopAfter(u32),
+    /// Panic on the first error.
+    Panic,
+}
+
+/// A bounded queue feeding the encoders of a [`Capture`] on a dedicated worker thread.
+///
+/// Stopping the capture waits for the queued frames to be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeQueue {
+    /// The maximum number of frames waiting to be encoded.
+    pub capacity: usize,
+    /// What happens when a frame is captured while the queue is full.
+    pub overflow: QueueOverflow,
+}
+
+impl Default for EncodeQueue {
+    fn default() -> Self {
+        Self {
+            capacity: 8,
+            overflow: QueueOverflow::default(),
+        }
+    }
+}
+
+/// What happens when a frame is captured while the [`EncodeQueue`] is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Block the render schedule until the worker has room for the frame.
+    #[default]
+    Block,
+    /// Drop the oldest queued frame to make room for the new one.
+    DropOldest,
+    /// Drop the new frame.
+    DropNewest,
+}
+
 #[derive(Default)]
 enum CaptureState {
     #[default]
     Idle,
     Capturing {
         encoders: Mutex<Option<Encoders>>,
+        depth_encoders: Mutex<Option<Encoders>>,
         paused: bool,
+        /// The number of frames to capture, `None` captures until stopped.
+        frame_limit: Option<u64>,
+        /// Set by the render world when the capture was stopped by its error policy.
+        stopped: Arc<AtomicBool>,
+        /// Set by the render world once `frame_limit` frames have been delivered.
+        completed: Arc<AtomicBool>,
     },
 }
 
@@ -105,20 +493,179 @@ struct Encoders(Vec<BoxedEncoder>);
 impl Drop for Encoders {
     fn drop(&mut self) {
         for encoder in self.0.drain(..) {
-            encoder.finish();
+            if let Err(err) = encoder.finish() {
+                bevy::log::error!("Failed to finish an encoder: {:?}", err);
+            }
+        }
+    }
+}
+
+/// A group of cameras that are captured in lockstep.
+///
+/// All members are extracted in the same app update and share the frame id. Their frames are
+/// delivered to the [`GroupEncoder`]s as a single [`FrameSet`](encoder::FrameSet). If the frame
+/// of any member can't be captured, e.g. because its target is not ready yet, the frame is
+/// dropped for all members.
+///
+/// The members are cameras, optionally with a [`CaptureSource`] to capture something else. Their
+/// own [`Capture`] is independent of the group, and the whole source is captured. Groups capture
+/// no depth and encode in the render schedule.
+///
+/// # Example
+/// ```ignore
+/// # use bevy::prelude::*;
+/// # use bevy_capture::{encoder::{frames::FramesEncoder, per_view::PerViewEncoder}, CaptureGroup};
+/// #
+/// fn setup(mut commands: Commands, cameras: Query<Entity, With<Camera>>) {
+///     let mut group = CaptureGroup::new(&cameras);
+///     group.start(PerViewEncoder::new((
+///         FramesEncoder::new("captures/cam0"),
+///         FramesEncoder::new("captures/cam1"),
+///     )));
+///     commands.spawn(group);
+/// }
+/// ```
+#[derive(Component)]
+pub struct CaptureGroup {
+    members: Vec<Entity>,
+    state: GroupState,
+    capture_rate: CaptureRate,
+    error_policy: ErrorPolicy,
+}
+
+impl CaptureGroup {
+    /// Creates a new group with the given members.
+    pub fn new(members: impl IntoIterator<Item = Entity>) -> Self {
+        Self {
+            members: members.into_iter().collect(),
+            state: GroupState::default(),
+            capture_rate: CaptureRate::default(),
+            error_policy: ErrorPolicy::default(),
+        }
+    }
+
+    /// Returns the members of the group.
+    pub fn members(&self) -> &[Entity] {
+        &self.members
+    }
+
+    /// Sets the [`CaptureRate`] of the group.
+    pub fn with_capture_rate(mut self, capture_rate: CaptureRate) -> Self {
+        self.capture_rate = capture_rate;
+        self
+    }
+
+    /// Sets the [`CaptureRate`] of the group. Takes effect with the next frame.
+    pub fn set_capture_rate(&mut self, capture_rate: CaptureRate) {
+        self.capture_rate = capture_rate;
+    }
+
+    /// Returns the [`CaptureRate`] of the group.
+    pub fn capture_rate(&self) -> CaptureRate {
+        self.capture_rate
+    }
+
+    /// Sets the [`ErrorPolicy`] of the group.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Sets the [`ErrorPolicy`] of the group. Takes effect with the next frame.
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    /// Returns the [`ErrorPolicy`] of the group.
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    /// Starts capturing frame sets with the given encoders. Replaces the encoders if the group
+    /// is already capturing, like [`Capture::start`].
+    pub fn start(&mut self, encoders: impl IntoGroupEncoders) {
+        self.state = GroupState::Capturing {
+            encoders: Mutex::new(Some(GroupEncoders(encoders.into_group_encoders()))),
+            paused: false,
+            stopped: Default::default(),
+        };
+    }
+
+    /// Pauses the group.
+    pub fn pause(&mut self) {
+        if let GroupState::Capturing { paused, .. } = &mut self.state {
+            *paused = true;
+        }
+    }
+
+    /// Resumes the group.
+    pub fn resume(&mut self) {
+        if let GroupState::Capturing { paused, .. } = &mut self.state {
+            *paused = false;
+        }
+    }
+
+    /// Stops the group. This will drop the active encoders, which will call
+    /// [`finish`](GroupEncoder::finish) on them.
+    pub fn stop(&mut self) {
+        self.state = GroupState::Idle;
+    }
+
+    /// Returns `true` if the group is currently capturing frames. This is `false` after the
+    /// group has been stopped by its [`ErrorPolicy`].
+    pub fn is_capturing(&self) -> bool {
+        matches!(&self.state, GroupState::Capturing { stopped, .. } if !stopped.load(Ordering::Acquire))
+    }
+
+    /// Returns `true` if the group is currently paused.
+    pub fn is_paused(&self) -> bool {
+        self.is_capturing() && matches!(&self.state, GroupState::Capturing { paused: true, .. })
+    }
+}
+
+#[derive(Default)]
+enum GroupState {
+    #[default]
+    Idle,
+    Capturing {
+        encoders: Mutex<Option<GroupEncoders>>,
+        paused: bool,
+        /// Set by the render world when the group was stopped by its error policy.
+        stopped: Arc<AtomicBool>,
+    },
+}
+
+struct GroupEncoders(Vec<BoxedGroupEncoder>);
+
+impl Drop for GroupEncoders {
+    fn drop(&mut self) {
+        for encoder in self.0.drain(..) {
+            if let Err(err) = encoder.finish() {
+                bevy::log::error!("Failed to finish an encoder: {:?}", err);
+            }
         }
     }
 }
 
 /// The source of the capture.
-#[derive(Default, Clone, Copy, Component)]
-#[non_exhaustive] // TODO: For windowed rendering: MainWindow, Window(Entity)
+#[derive(Default, Clone, Component)]
+#[non_exhaustive]
 pub enum CaptureSource {
     /// Use the camera of the entity this component is attached to.
     #[default]
     ThisCamera,
     /// Use the camera with the given entity.
     Camera(Entity),
+    /// Use the primary window. This captures everything that is presented to the window,
+    /// including gizmos and UI. Frames are resized along with the window.
+    MainWindow,
+    /// Use the window with the given entity.
+    Window(Entity),
+    /// Use the given image, e.g. the output of a custom render pass. The image needs
+    /// [`TextureUsages::COPY_SRC`], and [`TextureUsages::TEXTURE_BINDING`] if the
+    /// [`CaptureRegion`] is scaled. It is copied after all cameras have rendered. The frame
+    /// context is taken from the camera of this entity, if it is one.
+    Image(Handle<Image>),
 }
 
 /// Extension trait for the camera to set the target to a headless image.
@@ -135,10 +682,48 @@ pub enum CaptureSource {
 pub trait CameraTargetHeadless {
     /// Sets the target of the camera to a headless image with the given dimensions.
     fn target_headless(self, width: u32, height: u32, images: &mut Assets<Image>) -> Self;
+
+    /// Sets the target of the camera to a headless image with the given dimensions and format.
+    ///
+    /// For float formats like [`Rgba16Float`](TextureFormat::Rgba16Float) and
+    /// [`Rgba32Float`](TextureFormat::Rgba32Float) the camera is switched to hdr. To capture
+    /// the linear scene values, also bypass tonemapping and dithering on the camera:
+    ///
+    /// ```ignore
+    /// commands.spawn((
+    ///     Camera3d::default(),
+    ///     Camera::default().target_headless_with_format(
+    ///         512,
+    ///         512,
+    ///         TextureFormat::Rgba32Float,
+    ///         &mut images,
+    ///     ),
+    ///     Tonemapping::None,
+    ///     DebandDither::Disabled,
+    ///     CaptureBundle::default(),
+    /// ));
+    /// ```
+    fn target_headless_with_format(
+        self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        images: &mut Assets<Image>,
+    ) -> Self;
 }
 
 impl CameraTargetHeadless for Camera {
-    fn target_headless(mut self, width: u32, height: u32, images: &mut Assets<Image>) -> Self {
+    fn target_headless(self, width: u32, height: u32, images: &mut Assets<Image>) -> Self {
+        self.target_headless_with_format(width, height, TextureFormat::bevy_default(), images)
+    }
+
+    fn target_headless_with_format(
+        mut self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        images: &mut Assets<Image>,
+    ) -> Self {
         let mut image = Image::new_fill(
             Extent3d {
                 width,
@@ -146,8 +731,8 @@ impl CameraTargetHeadless for Camera {
                 depth_or_array_layers: 1,
             },
             TextureDimension::D2,
-            &[0; 4],
-            TextureFormat::bevy_default(),
+            &vec![0; format.pixel_size()],
+            format,
             RenderAssetUsages::default(),
         );
         image.texture_descriptor.usage |= TextureUsages::COPY_SRC
@@ -155,6 +740,12 @@ impl CameraTargetHeadless for Camera {
             | TextureUsages::TEXTURE_BINDING;
 
         self.target = RenderTarget::Image(images.add(image).into());
+        if matches!(
+            format,
+            TextureFormat::Rgba16Float | TextureFormat::Rgba32Float
+        ) {
+            self.hdr = true;
+        }
 
         self
     }
@@ -202,3 +793,46 @@ macro_rules! impl_into_encoders {
 }
 
 all_tuples!(impl_into_encoders, 0, 15, E, e);
+
+/// Convert a value into a sequence of group encoders.
+pub trait IntoGroupEncoders {
+    /// Converts the value into a sequence of group encoders.
+    fn into_group_encoders(self) -> Vec<BoxedGroupEncoder>;
+}
+
+impl IntoGroupEncoders for BoxedGroupEncoder {
+    fn into_group_encoders(self) -> Vec<BoxedGroupEncoder> {
+        vec![self]
+    }
+}
+
+impl IntoGroupEncoders for Vec<BoxedGroupEncoder> {
+    fn into_group_encoders(self) -> Vec<BoxedGroupEncoder> {
+        self
+    }
+}
+
+impl<E> IntoGroupEncoders for E
+where
+    E: GroupEncoder + Send + Sync + 'static,
+{
+    fn into_group_encoders(self) -> Vec<BoxedGroupEncoder> {
+        vec![Box::new(self)]
+    }
+}
+
+macro_rules! impl_into_group_encoders {
+    ($(($E:ident, $e:ident)),*) => {
+        impl<$($E),*> IntoGroupEncoders for ($($E,)*)
+        where
+            $($E: GroupEncoder + Send + Sync + 'static,)*
+        {
+            fn into_group_encoders(self) -> Vec<BoxedGroupEncoder> {
+                let ($($e,)*) = self;
+                vec![$(Box::new($e),)*]
+            }
+        }
+    };
+}
+
+all_tuples!(impl_into_group_encoders, 0, 15, E, e);
@@ -0,0 +1,73 @@
+//! The frame gate of the deterministic [`OfflineMode`](crate::OfflineMode).
+
+use bevy::prelude::*;
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// Shared between the main and the render world. The main world waits until the render world
+/// has delivered all frames it extracted to the encoders.
+#[derive(Resource, Clone)]
+pub(crate) struct FrameGate {
+    shared: Arc<(Mutex<GateState>, Condvar)>,
+    /// How long the main world waits before it continues without the frames, `None` to wait
+    /// forever. See [`OfflineMode::gate_timeout`](crate::OfflineMode::gate_timeout).
+    timeout: Option<Duration>,
+}
+
+#[derive(Default)]
+struct GateState {
+    /// The number of extracted app updates.
+    extracted: u64,
+    /// The number of app updates whose frames have been delivered to the encoders.
+    delivered: u64,
+}
+
+impl FrameGate {
+    pub(crate) fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            shared: Arc::default(),
+            timeout,
+        }
+    }
+
+    /// Called by the render world when an app update has been extracted.
+    pub(crate) fn extracted(&self) {
+        self.shared.0.lock().unwrap().extracted += 1;
+    }
+
+    /// Called by the render world when the frames of all extracted app updates have been
+    /// delivered to the encoders.
+    pub(crate) fn delivered(&self) {
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.delivered = state.extracted;
+        condvar.notify_all();
+    }
+
+    /// Blocks until all extracted frames have been delivered, or the timeout elapsed.
+    fn wait(&self) {
+        let (state, condvar) = &*self.shared;
+        let state = state.lock().unwrap();
+        let pending = |state: &mut GateState| state.delivered < state.extracted;
+        let Some(timeout) = self.timeout else {
+            drop(condvar.wait_while(state, pending).unwrap());
+            return;
+        };
+
+        let (_state, result) = condvar.wait_timeout_while(state, timeout, pending).unwrap();
+        if result.timed_out() {
+            bevy::log::warn!(
+                "Timed out after {:?} waiting for the captured frames, continuing without them",
+                timeout
+            );
+        }
+    }
+}
+
+/// Holds back the next simulation step until the frames of the previous one have reached the
+/// encoders.
+pub(crate) fn wait_for_captured_frames(gate: Res<FrameGate>) {
+    gate.wait();
+}
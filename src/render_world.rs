@@ -1,148 +1,1080 @@
-use crate::*;
+mod depth;
+mod encoding;
+mod scale;
+
+use crate::{
+    encoder::{FrameContext, FrameSet, FrameSetView, FrameView},
+    events::{CaptureEvent, CaptureEventSender, CaptureStarted},
+    offline::FrameGate,
+    *,
+};
 use bevy::{
-    ecs::entity::EntityHashMap,
-    image::TextureFormatPixelInfo,
+    asset::load_internal_asset,
+    core_pipeline::blit::{BlitPipeline, BlitPipelineKey},
+    ecs::{entity::EntityHashMap, system::SystemParam},
+    image::{BevyDefault, TextureFormatPixelInfo},
     prelude::*,
     render::{
+        camera::NormalizedRenderTarget,
         graph::CameraDriverLabel,
         render_asset::RenderAssets,
         render_graph::{self, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
         render_resource::{
-            Buffer, BufferDescriptor, BufferUsages, Maintain, MapMode, TexelCopyBufferInfo,
-            TexelCopyBufferLayout,
+            BindGroup, BindGroupEntries, Buffer, BufferDescriptor, BufferUsages,
+            CachedRenderPipelineId, CommandEncoder, LoadOp, Maintain, MapMode, Operations,
+            Origin3d, PipelineCache, RenderPassColorAttachment, RenderPassDescriptor,
+            SpecializedRenderPipelines, StoreOp, TexelCopyBufferInfo, TexelCopyBufferLayout,
+            TexelCopyTextureInfo, Texture, TextureAspect, TextureDescriptor, TextureView,
         },
         renderer::{RenderContext, RenderDevice},
-        texture::GpuImage,
+        sync_world::RenderEntity,
+        texture::{GpuImage, OutputColorAttachment},
+        view::{
+            prepare_view_attachments, prepare_view_targets, ExtractedWindows, ViewTargetAttachments,
+        },
         Extract, Render, RenderApp, RenderSet,
     },
+    window::{PrimaryWindow, WindowRef},
+};
+use depth::{DepthCapture, DepthPipeline, DEPTH_SHADER_HANDLE};
+use encoding::{CaptureEncoders, CaptureGroupEncoders, Encoding, FrameLimit};
+use scale::{ScalePipeline, ScaledTexture, SCALE_SHADER_HANDLE};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+/// Number of staging buffers per capture. Frames are delivered to the encoders up to
+/// `STAGING_BUFFERS - 1` frames after they were rendered.
+const STAGING_BUFFERS: usize = 3;
+
 pub struct CaptureRenderWorldPlugin;
 
 impl Plugin for CaptureRenderWorldPlugin {
     fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            DEPTH_SHADER_HANDLE,
+            "render_world/depth.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            SCALE_SHADER_HANDLE,
+            "render_world/scale.wgsl",
+            Shader::from_wgsl
+        );
+
         let render_app = app.sub_app_mut(RenderApp);
 
         render_app
             .init_resource::<Captures>()
+            .init_resource::<SpecializedRenderPipelines<DepthPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<ScalePipeline>>()
             .add_systems(ExtractSchedule, extract_captures);
 
         let mut graph = render_app.world_mut().resource_mut::<RenderGraph>();
         graph.add_node(ImageCopy, ImageCopyDriver);
         graph.add_node_edge(CameraDriverLabel, ImageCopy);
 
-        render_app.add_systems(Render, encode.after(RenderSet::Render));
+        render_app
+            .add_systems(
+                Render,
+                prepare_captures
+                    .in_set(RenderSet::ManageViews)
+                    .after(prepare_view_attachments)
+                    .before(prepare_view_targets),
+            )
+            .add_systems(
+                Render,
+                depth::prepare_depth_captures.in_set(RenderSet::PrepareBindGroups),
+            )
+            .add_systems(Render, encode.after(RenderSet::Render));
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<DepthPipeline>()
+            .init_resource::<ScalePipeline>();
     }
 }
 
 #[derive(Default, Resource)]
 struct Captures {
     captures: EntityHashMap<ExtractedCapture>,
+    groups: EntityHashMap<ExtractedGroup>,
+    /// The id of the next extracted frame, shared by all captures.
+    next_frame: u64,
+    /// Captures that were stopped but still have frames in flight. They are dropped (which
+    /// finishes their encoders) once all of their frames have been delivered.
+    draining: Vec<ExtractedCapture>,
+    /// Groups that were stopped but still have frames in flight, like `draining`.
+    draining_groups: Vec<ExtractedGroup>,
 }
 
 struct ExtractedCapture {
-    encoders: Encoders,
+    encoding: Encoding,
     paused: bool,
+    sampler: Sampler,
+    /// Whether the current frame is captured according to the [`CaptureRate`].
+    sampled: bool,
+    error_policy: ErrorPolicy,
+    /// Shared with the [`Capture`], set once the capture was stopped by the error policy.
+    stopped: Arc<AtomicBool>,
+    /// Shared with the [`Capture`], counts the dropped frames.
+    dropped_frames: Arc<AtomicU64>,
     state: Option<ExtractedCaptureState>,
+    /// States that were replaced because the source changed, oldest first. They are kept until
+    /// their frames in flight have been delivered.
+    retired: Vec<ExtractedCaptureState>,
+}
+
+impl ExtractedCapture {
+    /// Returns `true` if the current frame is captured.
+    fn is_active(&self) -> bool {
+        !self.paused && self.sampled
+    }
+
+    fn has_frames_in_flight(&self) -> bool {
+        self.state
+            .iter()
+            .chain(&self.retired)
+            .any(ExtractedCaptureState::has_frames_in_flight)
+    }
+}
+
+struct ExtractedGroup {
+    encoders: CaptureGroupEncoders,
+    paused: bool,
+    sampler: Sampler,
+    /// Whether the current frame is captured according to the [`CaptureRate`].
+    sampled: bool,
+    error_policy: ErrorPolicy,
+    /// Shared with the [`CaptureGroup`], set once the group was stopped by the error policy.
+    stopped: Arc<AtomicBool>,
+    /// The members in the order of the group, with their state if their source could be
+    /// resolved this frame.
+    members: Vec<(Entity, Option<ExtractedCaptureState>)>,
+}
+
+impl ExtractedGroup {
+    /// Returns `true` if the current frame is captured, which requires all members.
+    fn is_active(&self) -> bool {
+        !self.paused && self.sampled && self.members.iter().all(|(_, state)| state.is_some())
+    }
+
+    fn has_frames_in_flight(&self) -> bool {
+        self.members.iter().any(|(_, state)| {
+            state
+                .as_ref()
+                .is_some_and(ExtractedCaptureState::has_frames_in_flight)
+        })
+    }
+}
+
+/// Decides which frames are captured according to the [`CaptureRate`].
+#[derive(Default)]
+struct Sampler {
+    frames: u64,
+    /// The elapsed time at which the next frame is due, for [`CaptureRate::Hz`].
+    next_sample: Option<Duration>,
+}
+
+impl Sampler {
+    fn sample(&mut self, rate: CaptureRate, elapsed: Duration) -> bool {
+        match rate {
+            CaptureRate::EveryFrame => true,
+            CaptureRate::EveryNthFrame(n) => {
+                let sampled = self.frames.is_multiple_of(n.max(1) as u64);
+                self.frames += 1;
+                sampled
+            }
+            CaptureRate::Hz(hz) => {
+                let period = match Duration::try_from_secs_f64(1.0 / hz) {
+                    Ok(period) if !period.is_zero() => period,
+                    _ => return true,
+                };
+                let next_sample = self.next_sample.unwrap_or(elapsed);
+                if elapsed < next_sample {
+                    return false;
+                }
+
+                // Stay on the grid of sample times, skipping the ones that were missed
+                let due = (elapsed - next_sample).as_secs_f64() / period.as_secs_f64();
+                self.next_sample = Some(next_sample + period.mul_f64(due.floor() + 1.0));
+                true
+            }
+        }
+    }
+}
+
+/// The texture a capture reads from, resolved from its [`CaptureSource`].
+#[derive(Clone, PartialEq)]
+enum CaptureTarget {
+    Image(Handle<Image>),
+    Window(Entity),
 }
 
 struct ExtractedCaptureState {
-    source: Handle<Image>,
-    target_buffer: Buffer,
+    source: CaptureTarget,
+    source_size: Extent3d,
+    source_format: TextureFormat,
+    region: CaptureRegion,
+    /// The requested crop, clamped to the source.
+    crop: URect,
+    /// The texture the window is rendered to instead of its swap chain, if the source is a window.
+    window_texture: Option<WindowTexture>,
+    /// The texture the crop is scaled into, if the capture is scaled.
+    scaled: Option<ScaledTexture>,
+    staging: StagingRing,
+    /// Describes the size and format of the frames, which are passed to the encoders straight
+    /// from the staging buffers.
     target_image: Image,
+    /// The depth capture, if the capture has depth encoders.
+    depth: Option<DepthCapture>,
+    /// The context of the frame extracted last.
+    context: FrameContext,
+    /// The render world entity of the camera in the frame context.
+    render_camera: Entity,
 }
 
 impl ExtractedCaptureState {
-    fn init(source: Handle<Image>, images: &Assets<Image>, render_device: &RenderDevice) -> Self {
-        let source_image = images.get(&source).unwrap();
-        let size = source_image.texture_descriptor.size;
+    fn init(
+        source: CaptureTarget,
+        source_size: Extent3d,
+        format: TextureFormat,
+        region: CaptureRegion,
+        context: FrameContext,
+        render_device: &RenderDevice,
+        blit_pipeline: &BlitPipeline,
+    ) -> Self {
+        let window_texture = match source {
+            CaptureTarget::Window(_) => Some(WindowTexture::new(
+                source_size,
+                format,
+                render_device,
+                blit_pipeline,
+            )),
+            CaptureTarget::Image(_) => None,
+        };
+
+        let full = URect::new(0, 0, source_size.width, source_size.height);
+        let crop = match region.crop.map(|crop| crop.intersect(full)) {
+            Some(crop) if !crop.is_empty() => crop,
+            Some(_) => {
+                bevy::log::warn!("The capture crop is outside of the source, ignoring it");
+                full
+            }
+            None => full,
+        };
+        let scale = if region.scale > 0.0 {
+            region.scale
+        } else {
+            1.0
+        };
+        let size = Extent3d {
+            width: ((crop.width() as f32 * scale).round() as u32).max(1),
+            height: ((crop.height() as f32 * scale).round() as u32).max(1),
+            depth_or_array_layers: 1,
+        };
+        let scaled = (size.width != crop.width() || size.height != crop.height())
+            .then(|| ScaledTexture::new(crop, size, format, render_device));
 
         let padded_bytes_per_row =
-            RenderDevice::align_copy_bytes_per_row((size.width) as usize) * 4;
-        let target_buffer = render_device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: padded_bytes_per_row as u64 * size.height as u64,
-            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+            RenderDevice::align_copy_bytes_per_row(size.width as usize * format.pixel_size());
+        let staging = StagingRing::new(
+            padded_bytes_per_row as u64 * size.height as u64,
+            render_device,
+        );
 
-        let target_image = Image::new_fill(
+        let target_image = Image::new_uninit(
             size,
             TextureDimension::D2,
-            &[0; 4],
-            source_image.texture_descriptor.format,
+            format,
             RenderAssetUsages::default(),
         );
 
         Self {
             source,
-            target_buffer,
+            source_size,
+            source_format: format,
+            region,
+            crop,
+            window_texture,
+            scaled,
+            staging,
             target_image,
+            depth: None,
+            context,
+            render_camera: Entity::PLACEHOLDER,
         }
     }
+
+    fn has_frames_in_flight(&self) -> bool {
+        !self.staging.in_flight.is_empty()
+            || self
+                .depth
+                .as_ref()
+                .is_some_and(|depth| !depth.staging.in_flight.is_empty())
+    }
+}
+
+/// Captures of a window redirect the output of the cameras rendering to the window into this
+/// texture. After it has been copied, it is blitted to the swap chain.
+struct WindowTexture {
+    texture: Texture,
+    view: TextureView,
+    bind_group: BindGroup,
+    pipeline: Option<CachedRenderPipelineId>,
+}
+
+impl WindowTexture {
+    fn new(
+        size: Extent3d,
+        format: TextureFormat,
+        render_device: &RenderDevice,
+        blit_pipeline: &BlitPipeline,
+    ) -> Self {
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("capture_window_texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT
+                | TextureUsages::COPY_SRC
+                | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&Default::default());
+        let bind_group = render_device.create_bind_group(
+            "capture_window_blit_bind_group",
+            &blit_pipeline.texture_bind_group,
+            &BindGroupEntries::sequential((&view, &blit_pipeline.sampler)),
+        );
+
+        Self {
+            texture,
+            view,
+            bind_group,
+            pipeline: None,
+        }
+    }
+}
+
+/// A ring of staging buffers, so that the readback of a frame can overlap with rendering the
+/// following frames instead of blocking on the gpu.
+struct StagingRing {
+    buffers: Vec<StagingBuffer>,
+    /// The buffer the current frame is copied into, if any.
+    write: Option<usize>,
+    /// Buffers that are waiting to be mapped together with their frame context and when they
+    /// were submitted, oldest first.
+    in_flight: VecDeque<(usize, FrameContext, Instant)>,
+}
+
+struct StagingBuffer {
+    buffer: Buffer,
+    status: Arc<AtomicU8>,
+}
+
+const STAGING_FREE: u8 = 0;
+const STAGING_PENDING: u8 = 1;
+const STAGING_MAPPED: u8 = 2;
+const STAGING_FAILED: u8 = 3;
+
+impl StagingRing {
+    fn new(size: u64, render_device: &RenderDevice) -> Self {
+        let buffers = (0..STAGING_BUFFERS)
+            .map(|_| StagingBuffer {
+                buffer: render_device.create_buffer(&BufferDescriptor {
+                    label: Some("capture_staging_buffer"),
+                    size,
+                    usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                status: Arc::new(AtomicU8::new(STAGING_FREE)),
+            })
+            .collect();
+
+        Self {
+            buffers,
+            write: None,
+            in_flight: VecDeque::with_capacity(STAGING_BUFFERS),
+        }
+    }
+
+    /// Reserves a free buffer for the current frame. Returns `false` if all buffers are still
+    /// in flight, in which case the frame is skipped.
+    fn acquire(&mut self) -> bool {
+        self.write = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.status.load(Ordering::Acquire) == STAGING_FREE);
+        self.write.is_some()
+    }
+
+    /// Requests the mapping of the buffer written this frame. Must be called after the copy
+    /// commands have been submitted.
+    fn submit(&mut self, context: &FrameContext) {
+        let Some(slot) = self.write.take() else {
+            return;
+        };
+
+        let status = self.buffers[slot].status.clone();
+        status.store(STAGING_PENDING, Ordering::Release);
+        self.buffers[slot]
+            .buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                let next = match result {
+                    Ok(()) => STAGING_MAPPED,
+                    Err(_) => STAGING_FAILED,
+                };
+                status.store(next, Ordering::Release);
+            });
+
+        self.in_flight
+            .push_back((slot, context.clone(), Instant::now()));
+    }
+
+    /// Returns the id of the oldest frame and whether its buffer is mapped. Frames whose
+    /// mapping failed are skipped.
+    fn front(&mut self) -> Option<(u64, bool)> {
+        while let Some((slot, context, _)) = self.in_flight.front() {
+            let slot = *slot;
+            match self.buffers[slot].status.load(Ordering::Acquire) {
+                STAGING_FAILED => {
+                    bevy::log::error!(
+                        "Failed to map the staging buffer of frame {}",
+                        context.frame
+                    );
+                    self.in_flight.pop_front();
+                    self.buffers[slot]
+                        .status
+                        .store(STAGING_FREE, Ordering::Release);
+                }
+                status => return Some((context.frame, status == STAGING_MAPPED)),
+            }
+        }
+        None
+    }
+
+    /// Returns the oldest frame and its readback latency if its buffer is mapped. Frames whose
+    /// mapping failed are skipped. The buffer must be handed back with
+    /// [`release`](Self::release).
+    fn next_mapped(&mut self) -> Option<(usize, FrameContext, Duration)> {
+        match self.front()? {
+            (_, true) => self
+                .in_flight
+                .pop_front()
+                .map(|(slot, context, submitted)| (slot, context, submitted.elapsed())),
+            _ => None,
+        }
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.buffers[slot].buffer.unmap();
+        self.buffers[slot]
+            .status
+            .store(STAGING_FREE, Ordering::Release);
+    }
+}
+
+/// The main world data used to resolve the source of a capture.
+#[derive(SystemParam)]
+struct CaptureSources<'w, 's> {
+    sources_query: Extract<'w, 's, Query<'static, 'static, Option<&'static CaptureSource>>>,
+    cameras_query: Extract<
+        'w,
+        's,
+        Query<'static, 'static, (Entity, &'static Camera, &'static GlobalTransform)>,
+    >,
+    render_entities: Extract<'w, 's, Query<'static, 'static, &'static RenderEntity>>,
+    windows_query:
+        Extract<'w, 's, Query<'static, 'static, (Entity, &'static Window, Has<PrimaryWindow>)>>,
+    images: Extract<'w, 's, Res<'static, Assets<Image>>>,
+    time: Extract<'w, 's, Res<'static, Time>>,
+    render_device: Res<'w, RenderDevice>,
+    blit_pipeline: Res<'w, BlitPipeline>,
+}
+
+impl CaptureSources<'_, '_> {
+    /// Resolves the source of a capture and snapshots the context of the current frame. The
+    /// state of the previous frame is reused if the source is unchanged, including its size and
+    /// format. Otherwise the previous state is moved to `retired` if it has frames in flight.
+    /// Returns `None` if the source can't be resolved.
+    fn extract_state(
+        &self,
+        entity: Entity,
+        capture_source: &CaptureSource,
+        region: CaptureRegion,
+        frame: u64,
+        prev_state: Option<ExtractedCaptureState>,
+        retired: &mut Vec<ExtractedCaptureState>,
+    ) -> Option<ExtractedCaptureState> {
+        let mut retire = |prev_state: Option<ExtractedCaptureState>| {
+            retired.extend(prev_state.filter(ExtractedCaptureState::has_frames_in_flight));
+        };
+
+        let Some((source, camera_entity, size, format)) = self.resolve(entity, capture_source)
+        else {
+            retire(prev_state);
+            return None;
+        };
+
+        let context = match camera_entity {
+            Some(camera_entity) => {
+                let (_, camera, transform) = self.cameras_query.get(camera_entity).unwrap();
+                FrameContext {
+                    frame,
+                    elapsed: self.time.elapsed(),
+                    camera: camera_entity,
+                    transform: *transform,
+                    projection: camera.clip_from_view(),
+                    viewport: camera.physical_viewport_rect(),
+                }
+            }
+            // An image source without a camera
+            None => FrameContext {
+                frame,
+                elapsed: self.time.elapsed(),
+                camera: entity,
+                transform: GlobalTransform::IDENTITY,
+                projection: Mat4::IDENTITY,
+                viewport: None,
+            },
+        };
+        let render_camera = camera_entity
+            .and_then(|camera_entity| self.render_entities.get(camera_entity).ok())
+            .map_or(Entity::PLACEHOLDER, |render_entity| render_entity.id());
+        let mut state = match prev_state {
+            Some(prev_state)
+                if prev_state.source == source
+                    && prev_state.source_size == size
+                    && prev_state.source_format == format
+                    && prev_state.region == region =>
+            {
+                prev_state
+            }
+            prev_state => {
+                // E.g. the target image was resized, the staging buffers and the target image
+                // are recreated for the new layout
+                retire(prev_state);
+                ExtractedCaptureState::init(
+                    source,
+                    size,
+                    format,
+                    region,
+                    context.clone(),
+                    &self.render_device,
+                    &self.blit_pipeline,
+                )
+            }
+        };
+        state.context = context;
+        state.render_camera = render_camera;
+
+        Some(state)
+    }
+
+    /// Resolves the source of a capture, together with the camera used for the frame context
+    /// and the size and format of the source.
+    fn resolve(
+        &self,
+        entity: Entity,
+        capture_source: &CaptureSource,
+    ) -> Option<(CaptureTarget, Option<Entity>, Extent3d, TextureFormat)> {
+        let primary_window = self
+            .windows_query
+            .iter()
+            .find_map(|(entity, _, primary)| primary.then_some(entity));
+
+        let (source, camera_entity) = match capture_source {
+            CaptureSource::ThisCamera => image_target(entity, &self.cameras_query),
+            CaptureSource::Camera(camera) => image_target(*camera, &self.cameras_query),
+            CaptureSource::MainWindow => primary_window.and_then(|window| {
+                window_target(entity, window, primary_window, &self.cameras_query)
+            }),
+            CaptureSource::Window(window) => {
+                window_target(entity, *window, primary_window, &self.cameras_query)
+            }
+            CaptureSource::Image(image) => {
+                let camera = self.cameras_query.contains(entity).then_some(entity);
+                return self.images.get(image).and_then(|image_asset| {
+                    let descriptor = &image_asset.texture_descriptor;
+                    if !descriptor.usage.contains(TextureUsages::COPY_SRC) {
+                        bevy::log::warn_once!(
+                            "The image captured by {entity} needs TextureUsages::COPY_SRC"
+                        );
+                        return None;
+                    }
+                    Some((
+                        CaptureTarget::Image(image.clone()),
+                        camera,
+                        descriptor.size,
+                        descriptor.format,
+                    ))
+                });
+            }
+        }?;
+        let (size, format) = match &source {
+            CaptureTarget::Image(image) => self.images.get(image).map(|image| {
+                (
+                    image.texture_descriptor.size,
+                    image.texture_descriptor.format,
+                )
+            }),
+            CaptureTarget::Window(window) => {
+                self.windows_query.get(*window).ok().map(|(_, window, _)| {
+                    let size = Extent3d {
+                        width: window.resolution.physical_width().max(1),
+                        height: window.resolution.physical_height().max(1),
+                        depth_or_array_layers: 1,
+                    };
+                    (size, TextureFormat::bevy_default())
+                })
+            }
+        }?;
+
+        Some((source, Some(camera_entity), size, format))
+    }
 }
 
 fn extract_captures(
     mut captures: ResMut<Captures>,
     captures_query: Extract<Query<(Entity, &Capture, &CaptureSource)>>,
-    cameras_query: Extract<Query<&Camera>>,
-    images: Extract<Res<Assets<Image>>>,
-    render_device: Res<RenderDevice>,
+    groups_query: Extract<Query<(Entity, &CaptureGroup)>>,
+    sources: CaptureSources,
+    events: Res<CaptureEventSender>,
+    gate: Option<Res<FrameGate>>,
 ) {
-    captures.captures = captures_query
+    let frame = captures.next_frame;
+    captures.next_frame += 1;
+
+    if let Some(gate) = gate {
+        gate.extracted();
+    }
+
+    let extracted = captures_query
         .iter()
         .filter_map(|(entity, capture, capture_source)| match &capture.state {
             CaptureState::Idle => None,
-            CaptureState::Capturing { encoders, paused } => {
-                let (prev_encoder, prev_state) = match captures.captures.remove(&entity) {
-                    Some(extracted) => (Some(extracted.encoders), extracted.state),
-                    None => (None, None),
+            // Stopped by the error policy or complete, finish it like a stopped capture
+            CaptureState::Capturing {
+                stopped, completed, ..
+            } if stopped.load(Ordering::Acquire) || completed.load(Ordering::Acquire) => None,
+            CaptureState::Capturing {
+                encoders,
+                depth_encoders,
+                paused,
+                frame_limit,
+                stopped,
+                completed,
+            } => {
+                // Started again with new encoders while capturing, the previous encoders are
+                // finished once their frames in flight have been delivered. The capture keeps
+                // running, so neither CaptureFinished nor CaptureStarted is sent.
+                let new_encoders = encoders.lock().unwrap().take();
+                let prev = captures.captures.remove(&entity);
+                let replaced = prev.is_some() && new_encoders.is_some();
+                let prev = match prev {
+                    Some(prev) if new_encoders.is_some() => {
+                        prev.encoding.replaced();
+                        if prev.has_frames_in_flight() {
+                            captures.draining.push(prev);
+                        }
+                        None
+                    }
+                    prev => prev,
                 };
 
-                let encoders =
-                    prev_encoder.unwrap_or_else(|| encoders.lock().unwrap().take().unwrap());
-
-                let camera_entity = match capture_source {
-                    CaptureSource::ThisCamera => entity,
-                    CaptureSource::Camera(entity) => *entity,
+                let (prev_encoding, mut sampler, prev_state, mut retired) = match prev {
+                    Some(extracted) => (
+                        Some(extracted.encoding),
+                        extracted.sampler,
+                        extracted.state,
+                        extracted.retired,
+                    ),
+                    None => (None, Sampler::default(), None, Vec::new()),
                 };
-                let source = cameras_query
-                    .get(camera_entity)
-                    .ok()
-                    .and_then(|camera| match &camera.target {
-                        RenderTarget::Image(image) => Some(image.clone()),
-                        _ => None,
-                    });
-                let source = match source {
-                    Some(source) => source.handle,
-                    None => {
-                        return Some((
+                let sampled = sampler.sample(capture.capture_rate(), sources.time.elapsed());
+
+                let encoding = prev_encoding.unwrap_or_else(|| {
+                    if !replaced {
+                        events.send(CaptureEvent::Started(CaptureStarted { entity }));
+                    }
+                    Encoding::new(
+                        CaptureEncoders::new(
                             entity,
-                            ExtractedCapture {
-                                encoders,
-                                paused: *paused,
-                                state: None,
-                            },
-                        ))
+                            new_encoders.unwrap(),
+                            depth_encoders.lock().unwrap().take(),
+                            frame_limit.map(|frames| FrameLimit {
+                                frames,
+                                completed: completed.clone(),
+                            }),
+                            stopped.clone(),
+                            events.clone(),
+                        ),
+                        capture.encode_queue(),
+                        capture.dropped_frames.clone(),
+                    )
+                });
+
+                let mut state = sources.extract_state(
+                    entity,
+                    capture_source,
+                    capture.region(),
+                    frame,
+                    prev_state,
+                    &mut retired,
+                );
+                if let Some(state) = &mut state {
+                    if encoding.has_depth() && state.depth.is_none() {
+                        state.depth =
+                            Some(DepthCapture::new(state.source_size, &sources.render_device));
+                    }
+                }
+
+                Some((
+                    entity,
+                    ExtractedCapture {
+                        encoding,
+                        paused: *paused,
+                        sampler,
+                        sampled,
+                        error_policy: capture.error_policy(),
+                        stopped: stopped.clone(),
+                        dropped_frames: capture.dropped_frames.clone(),
+                        state,
+                        retired,
+                    },
+                ))
+            }
+        })
+        .collect();
+
+    let extracted_groups = groups_query
+        .iter()
+        .filter_map(|(entity, group)| match &group.state {
+            GroupState::Idle => None,
+            GroupState::Capturing { stopped, .. } if stopped.load(Ordering::Acquire) => None,
+            GroupState::Capturing {
+                encoders,
+                paused,
+                stopped,
+            } => {
+                // Started again with new encoders while capturing, like captures
+                let new_encoders = encoders.lock().unwrap().take();
+                let prev = captures.groups.remove(&entity);
+                let replaced = prev.is_some() && new_encoders.is_some();
+                let prev = match prev {
+                    Some(prev) if new_encoders.is_some() => {
+                        prev.encoders.replaced();
+                        if prev.has_frames_in_flight() {
+                            captures.draining_groups.push(prev);
+                        }
+                        None
                     }
+                    prev => prev,
                 };
 
-                let state = match prev_state {
-                    Some(prev_state) if prev_state.source == source => prev_state,
-                    _ => ExtractedCaptureState::init(source, &images, &render_device),
+                let (prev_encoders, mut sampler, mut prev_members) = match prev {
+                    Some(extracted) => (
+                        Some(extracted.encoders),
+                        extracted.sampler,
+                        extracted.members,
+                    ),
+                    None => (None, Sampler::default(), Vec::new()),
                 };
+                let sampled = sampler.sample(group.capture_rate(), sources.time.elapsed());
+
+                let encoders = prev_encoders.unwrap_or_else(|| {
+                    if !replaced {
+                        events.send(CaptureEvent::Started(CaptureStarted { entity }));
+                    }
+                    CaptureGroupEncoders::new(
+                        entity,
+                        new_encoders.unwrap(),
+                        stopped.clone(),
+                        events.clone(),
+                    )
+                });
+
+                // All members are extracted together, so they share the frame and the frame is
+                // only captured if all of them could be resolved. Frames in flight of replaced
+                // member states are dropped, the sets they belong to can't be completed.
+                let members = group
+                    .members()
+                    .iter()
+                    .map(|&member| {
+                        let prev_state = prev_members
+                            .iter()
+                            .position(|(prev_member, _)| *prev_member == member)
+                            .and_then(|index| prev_members.swap_remove(index).1);
+                        let capture_source = sources
+                            .sources_query
+                            .get(member)
+                            .ok()
+                            .flatten()
+                            .cloned()
+                            .unwrap_or_default();
+                        let state = sources.extract_state(
+                            member,
+                            &capture_source,
+                            CaptureRegion::default(),
+                            frame,
+                            prev_state,
+                            &mut Vec::new(),
+                        );
+                        (member, state)
+                    })
+                    .collect();
 
                 Some((
                     entity,
-                    ExtractedCapture {
+                    ExtractedGroup {
                         encoders,
                         paused: *paused,
-                        state: Some(state),
+                        sampler,
+                        sampled,
+                        error_policy: group.error_policy(),
+                        stopped: stopped.clone(),
+                        members,
                     },
                 ))
             }
         })
         .collect();
+
+    // Keep stopped captures around until their remaining frames have been read back
+    let stopped = std::mem::replace(&mut captures.captures, extracted);
+    captures.draining.extend(
+        stopped
+            .into_iter()
+            .map(|(_, capture)| capture)
+            .filter(ExtractedCapture::has_frames_in_flight),
+    );
+    let stopped = std::mem::replace(&mut captures.groups, extracted_groups);
+    captures.draining_groups.extend(
+        stopped
+            .into_iter()
+            .map(|(_, group)| group)
+            .filter(ExtractedGroup::has_frames_in_flight),
+    );
+}
+
+/// Resolves the image target of a camera.
+fn image_target(
+    camera: Entity,
+    cameras_query: &Query<(Entity, &Camera, &GlobalTransform)>,
+) -> Option<(CaptureTarget, Entity)> {
+    let (_, camera_component, _) = cameras_query.get(camera).ok()?;
+    match &camera_component.target {
+        RenderTarget::Image(image) => Some((CaptureTarget::Image(image.handle.clone()), camera)),
+        _ => None,
+    }
+}
+
+/// Resolves a window target together with the camera used for the frame context. This is the
+/// capture entity itself if it is a camera rendering to the window, otherwise any camera
+/// rendering to the window.
+fn window_target(
+    entity: Entity,
+    window: Entity,
+    primary_window: Option<Entity>,
+    cameras_query: &Query<(Entity, &Camera, &GlobalTransform)>,
+) -> Option<(CaptureTarget, Entity)> {
+    let renders_to_window = |camera: &Camera| {
+        matches!(
+            camera.target.normalize(primary_window),
+            Some(NormalizedRenderTarget::Window(target)) if target.entity() == window
+        )
+    };
+
+    let camera = match cameras_query.get(entity) {
+        Ok((_, camera, _)) if renders_to_window(camera) => entity,
+        _ => cameras_query
+            .iter()
+            .find_map(|(camera_entity, camera, _)| {
+                renders_to_window(camera).then_some(camera_entity)
+            })?,
+    };
+
+    Some((CaptureTarget::Window(window), camera))
+}
+
+/// The resources used to prepare the frame of a capture.
+#[derive(SystemParam)]
+struct PrepareResources<'w> {
+    windows: Res<'w, ExtractedWindows>,
+    gpu_images: Res<'w, RenderAssets<GpuImage>>,
+    blit_pipeline: Res<'w, BlitPipeline>,
+    blit_pipelines: ResMut<'w, SpecializedRenderPipelines<BlitPipeline>>,
+    scale_pipeline: Res<'w, ScalePipeline>,
+    scale_pipelines: ResMut<'w, SpecializedRenderPipelines<ScalePipeline>>,
+    pipeline_cache: Res<'w, PipelineCache>,
+}
+
+impl PrepareResources<'_> {
+    /// Reserves a staging buffer for the current frame of a capture. Returns `false` if the
+    /// frame is skipped, because a pipeline, the image or the window is not ready yet or all
+    /// staging buffers are in flight. The latter counts as a dropped frame.
+    fn prepare(
+        &mut self,
+        capture_state: &mut ExtractedCaptureState,
+        dropped_frames: Option<&AtomicU64>,
+    ) -> bool {
+        if let Some(scaled) = &mut capture_state.scaled {
+            let pipeline = self.scale_pipelines.specialize(
+                &self.pipeline_cache,
+                &self.scale_pipeline,
+                capture_state.target_image.texture_descriptor.format,
+            );
+            scaled.pipeline = Some(pipeline);
+            if self.pipeline_cache.get_render_pipeline(pipeline).is_none() {
+                return false;
+            }
+        }
+
+        if let CaptureTarget::Image(image) = &capture_state.source {
+            // E.g. an image that was just added and not uploaded yet
+            let Some(gpu_image) = self.gpu_images.get(image) else {
+                return false;
+            };
+            // After a resize the upload can lag behind the image, copying the old texture with
+            // the new size would be invalid
+            let size = capture_state.source_size;
+            if (gpu_image.size.width, gpu_image.size.height) != (size.width, size.height)
+                || gpu_image.texture_format != capture_state.source_format
+            {
+                bevy::log::debug!("The image was not uploaded after a change yet, skipping frame");
+                return false;
+            }
+        }
+
+        if let (CaptureTarget::Window(window), Some(window_texture)) =
+            (&capture_state.source, &mut capture_state.window_texture)
+        {
+            // Skip the frame if the swap chain is not available or the blit pipeline is not
+            // ready yet, the window is then rendered as usual
+            let Some(swap_chain_format) = self.windows.get(window).and_then(|window| {
+                window
+                    .swap_chain_texture_view
+                    .as_ref()
+                    .and(window.swap_chain_texture_format)
+            }) else {
+                return false;
+            };
+            let pipeline = self.blit_pipelines.specialize(
+                &self.pipeline_cache,
+                &self.blit_pipeline,
+                BlitPipelineKey {
+                    texture_format: swap_chain_format.add_srgb_suffix(),
+                    blend_state: None,
+                    samples: 1,
+                },
+            );
+            window_texture.pipeline = Some(pipeline);
+            if self.pipeline_cache.get_render_pipeline(pipeline).is_none() {
+                return false;
+            }
+        }
+
+        // The color and depth frames are reserved together, so that they stay paired
+        let acquired = capture_state.staging.acquire()
+            && capture_state
+                .depth
+                .as_mut()
+                .is_none_or(|depth| depth.staging.acquire());
+        if !acquired {
+            bevy::log::debug!("All staging buffers are in flight, skipping frame");
+            capture_state.staging.write = None;
+            if let Some(depth) = &mut capture_state.depth {
+                depth.staging.write = None;
+            }
+            if let Some(dropped_frames) = dropped_frames {
+                dropped_frames.fetch_add(1, Ordering::Relaxed);
+            }
+            return false;
+        }
+        true
+    }
+}
+
+/// Renders the window of a prepared capture into the capture texture instead of the swap chain.
+fn redirect_window(
+    capture_state: &ExtractedCaptureState,
+    view_target_attachments: &mut ViewTargetAttachments,
+) {
+    let (CaptureTarget::Window(window), Some(window_texture)) =
+        (&capture_state.source, &capture_state.window_texture)
+    else {
+        return;
+    };
+
+    let window_ref = WindowRef::Entity(*window).normalize(None).unwrap();
+    view_target_attachments.insert(
+        NormalizedRenderTarget::Window(window_ref),
+        OutputColorAttachment::new(
+            window_texture.view.clone(),
+            capture_state
+                .target_image
+                .texture_descriptor
+                .format
+                .add_srgb_suffix(),
+        ),
+    );
+}
+
+fn prepare_captures(
+    mut captures: ResMut<Captures>,
+    mut resources: PrepareResources,
+    mut view_target_attachments: ResMut<ViewTargetAttachments>,
+) {
+    let captures = captures.as_mut();
+
+    for capture in captures.captures.values_mut() {
+        if !capture.is_active() {
+            continue;
+        }
+        let Some(capture_state) = &mut capture.state else {
+            continue;
+        };
+
+        if resources.prepare(capture_state, Some(&capture.dropped_frames)) {
+            redirect_window(capture_state, &mut view_target_attachments);
+        }
+    }
+
+    for group in captures.groups.values_mut() {
+        if !group.is_active() {
+            continue;
+        }
+
+        // Capture the frame for all members or none of them
+        let prepared = group
+            .members
+            .iter_mut()
+            .filter_map(|(_, state)| state.as_mut())
+            .all(|state| resources.prepare(state, None));
+        for state in group
+            .members
+            .iter_mut()
+            .filter_map(|(_, state)| state.as_mut())
+        {
+            if prepared {
+                redirect_window(state, &mut view_target_attachments);
+            } else {
+                state.staging.write = None;
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash, RenderLabel)]
@@ -159,111 +1091,498 @@ impl render_graph::Node for ImageCopyDriver {
         world: &World,
     ) -> Result<(), NodeRunError> {
         let captures = world.get_resource::<Captures>().unwrap();
-        let gpu_images = world.get_resource::<RenderAssets<GpuImage>>().unwrap();
+        let resources = CopyResources {
+            gpu_images: world.get_resource::<RenderAssets<GpuImage>>().unwrap(),
+            windows: world.get_resource::<ExtractedWindows>().unwrap(),
+            pipeline_cache: world.get_resource::<PipelineCache>().unwrap(),
+            scale_pipeline: world.get_resource::<ScalePipeline>().unwrap(),
+            render_device: world.get_resource::<RenderDevice>().unwrap(),
+        };
 
         for capture in captures.captures.values() {
             let capture_state = match &capture.state {
-                Some(state) if !capture.paused => state,
+                Some(state) if capture.is_active() => state,
                 _ => continue,
             };
 
-            let src_image = gpu_images.get(&capture_state.source).unwrap();
-
             let encoder = render_context.command_encoder();
 
-            let block_dimensions = src_image.texture_format.block_dimensions();
-            let block_size = src_image.texture_format.block_copy_size(None).unwrap();
+            if let Some(depth) = &capture_state.depth {
+                depth.render(encoder, resources.pipeline_cache);
+            }
 
-            // Calculating correct size of image row because
-            // copy_texture_to_buffer can copy image only by rows aligned wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
-            // That's why image in buffer can be little bit wider
-            // This should be taken into account at copy from buffer stage
-            let padded_bytes_per_row = RenderDevice::align_copy_bytes_per_row(
-                (src_image.size.width as usize / block_dimensions.0 as usize) * block_size as usize,
-            );
+            resources.copy(capture_state, encoder);
+        }
+
+        for group in captures.groups.values() {
+            if !group.is_active() {
+                continue;
+            }
+
+            for (_, capture_state) in &group.members {
+                if let Some(capture_state) = capture_state {
+                    resources.copy(capture_state, render_context.command_encoder());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The resources used to copy the frame of a capture.
+struct CopyResources<'a> {
+    gpu_images: &'a RenderAssets<GpuImage>,
+    windows: &'a ExtractedWindows,
+    pipeline_cache: &'a PipelineCache,
+    scale_pipeline: &'a ScalePipeline,
+    render_device: &'a RenderDevice,
+}
 
-            let texture_extent = Extent3d {
-                width: src_image.size.width,
-                height: src_image.size.height,
-                depth_or_array_layers: 1,
+impl CopyResources<'_> {
+    /// Copies the current frame of a capture into its reserved staging buffer and presents the
+    /// captured window texture.
+    fn copy(&self, capture_state: &ExtractedCaptureState, encoder: &mut CommandEncoder) {
+        let Some(slot) = capture_state.staging.write else {
+            return;
+        };
+
+        let (src_texture, src_view) = match (&capture_state.source, &capture_state.window_texture) {
+            (CaptureTarget::Window(_), Some(window_texture)) => {
+                (&window_texture.texture, &window_texture.view)
+            }
+            (CaptureTarget::Image(image), _) => {
+                let src_image = self.gpu_images.get(image).unwrap();
+                (&src_image.texture, &src_image.texture_view)
+            }
+            _ => return,
+        };
+
+        // Crop and scale on the gpu, so that only the reduced image is read back
+        let (copy_texture, copy_origin) = match &capture_state.scaled {
+            Some(scaled) => {
+                scaled.render(
+                    encoder,
+                    src_view,
+                    self.scale_pipeline,
+                    self.pipeline_cache,
+                    self.render_device,
+                );
+                (&scaled.texture, UVec2::ZERO)
+            }
+            None => (src_texture, capture_state.crop.min),
+        };
+
+        copy_to_staging(
+            encoder,
+            copy_texture,
+            copy_origin,
+            capture_state.target_image.texture_descriptor.size,
+            capture_state.target_image.texture_descriptor.format,
+            &capture_state.staging.buffers[slot].buffer,
+        );
+
+        // Present the captured window texture
+        if let (CaptureTarget::Window(window), Some(window_texture)) =
+            (&capture_state.source, &capture_state.window_texture)
+        {
+            let Some(swap_chain_view) = self
+                .windows
+                .get(window)
+                .and_then(|window| window.swap_chain_texture_view.as_ref())
+            else {
+                return;
+            };
+            let Some(pipeline) = window_texture
+                .pipeline
+                .and_then(|pipeline| self.pipeline_cache.get_render_pipeline(pipeline))
+            else {
+                return;
             };
 
-            encoder.copy_texture_to_buffer(
-                src_image.texture.as_image_copy(),
-                TexelCopyBufferInfo {
-                    buffer: &capture_state.target_buffer,
-                    layout: TexelCopyBufferLayout {
-                        offset: 0,
-                        bytes_per_row: Some(
-                            std::num::NonZeroU32::new(padded_bytes_per_row as u32)
-                                .unwrap()
-                                .into(),
-                        ),
-                        rows_per_image: None,
+            let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("capture_window_blit_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: swap_chain_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Default::default()),
+                        store: StoreOp::Store,
                     },
-                },
-                texture_extent,
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &window_texture.bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+/// Copies the region of a texture starting at `origin` into a staging buffer.
+fn copy_to_staging(
+    encoder: &mut CommandEncoder,
+    texture: &Texture,
+    origin: UVec2,
+    size: Extent3d,
+    format: TextureFormat,
+    buffer: &Buffer,
+) {
+    let block_dimensions = format.block_dimensions();
+    let block_size = format.block_copy_size(None).unwrap();
+
+    // Calculating correct size of image row because
+    // copy_texture_to_buffer can copy image only by rows aligned wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+    // That's why image in buffer can be little bit wider
+    // This should be taken into account at copy from buffer stage
+    let padded_bytes_per_row = RenderDevice::align_copy_bytes_per_row(
+        (size.width as usize / block_dimensions.0 as usize) * block_size as usize,
+    );
+
+    let texture_extent = Extent3d {
+        width: size.width,
+        height: size.height,
+        depth_or_array_layers: 1,
+    };
+
+    encoder.copy_texture_to_buffer(
+        TexelCopyTextureInfo {
+            texture,
+            mip_level: 0,
+            origin: Origin3d {
+                x: origin.x,
+                y: origin.y,
+                z: 0,
+            },
+            aspect: TextureAspect::All,
+        },
+        TexelCopyBufferInfo {
+            buffer,
+            layout: TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(
+                    std::num::NonZeroU32::new(padded_bytes_per_row as u32)
+                        .unwrap()
+                        .into(),
+                ),
+                rows_per_image: None,
+            },
+        },
+        texture_extent,
+    );
+}
+
+fn encode(
+    mut captures: ResMut<Captures>,
+    render_device: Res<RenderDevice>,
+    gate: Option<Res<FrameGate>>,
+) {
+    let captures = captures.as_mut();
+
+    // Request the readback of this frame's copies
+    let group_states = captures
+        .groups
+        .values_mut()
+        .flat_map(|group| group.members.iter_mut())
+        .filter_map(|(_, state)| state.as_mut());
+    for state in captures
+        .captures
+        .values_mut()
+        .filter_map(|capture| capture.state.as_mut())
+        .chain(group_states)
+    {
+        state.staging.submit(&state.context);
+        if let Some(depth) = &mut state.depth {
+            depth.staging.submit(&state.context);
+        }
+    }
+
+    if gate.is_some() {
+        // Offline, all frames of this update must reach the encoders before the next one
+        render_device.poll(Maintain::Wait);
+    } else {
+        // Never block on the gpu, just process whatever readbacks have completed by now
+        render_device.poll(Maintain::Poll);
+    }
+
+    for capture in captures
+        .captures
+        .values_mut()
+        .chain(captures.draining.iter_mut())
+    {
+        // Frames of replaced states are older, deliver them first
+        for capture_state in capture.retired.iter_mut().chain(&mut capture.state) {
+            let delivered = deliver_frames(
+                capture_state,
+                &mut capture.encoding,
+                &capture.stopped,
+                capture.error_policy,
             );
+            if !delivered {
+                break;
+            }
         }
+        capture
+            .retired
+            .retain(ExtractedCaptureState::has_frames_in_flight);
+    }
 
-        Ok(())
+    for group in captures
+        .groups
+        .values_mut()
+        .chain(captures.draining_groups.iter_mut())
+    {
+        encode_group(group);
     }
+
+    if let Some(gate) = gate {
+        for capture in captures.captures.values().chain(&captures.draining) {
+            capture.encoding.flush();
+        }
+        gate.delivered();
+    }
+
+    // Drop drained captures, which finishes their encoders
+    captures
+        .draining
+        .retain(ExtractedCapture::has_frames_in_flight);
+    captures
+        .draining_groups
+        .retain(ExtractedGroup::has_frames_in_flight);
 }
 
-fn encode(mut captures: ResMut<Captures>, render_device: Res<RenderDevice>) {
-    for capture in captures.captures.values_mut() {
-        let capture_state = match &mut capture.state {
-            Some(state) if !capture.paused => state,
-            _ => continue,
+/// Passes the frames of a capture state that have been read back to the encoders. Returns
+/// `false` if frames are still in flight.
+fn deliver_frames(
+    capture_state: &mut ExtractedCaptureState,
+    encoding: &mut Encoding,
+    stopped: &AtomicBool,
+    error_policy: ErrorPolicy,
+) -> bool {
+    loop {
+        let color = capture_state.staging.front();
+        let depth = capture_state
+            .depth
+            .as_mut()
+            .and_then(|depth| depth.staging.front());
+        // The color and depth frames of the same frame are delivered together, a frame is
+        // only missing from one of the rings if its mapping failed
+        let Some(frame) = color.into_iter().chain(depth).map(|(frame, _)| frame).min() else {
+            break;
         };
+        let pending =
+            |front: Option<(u64, bool)>| front.is_some_and(|front| front == (frame, false));
+        if pending(color) || pending(depth) {
+            break;
+        }
 
-        // Get the data back from the gpu
-        let buffer_slice = capture_state.target_buffer.slice(..);
+        if color.is_some_and(|(color_frame, _)| color_frame == frame) {
+            deliver_frame(
+                &mut capture_state.staging,
+                &capture_state.target_image,
+                false,
+                encoding,
+                stopped,
+                error_policy,
+            );
+        }
+        if let Some(capture_depth) = &mut capture_state.depth {
+            if depth.is_some_and(|(depth_frame, _)| depth_frame == frame) {
+                deliver_frame(
+                    &mut capture_depth.staging,
+                    &capture_depth.target_image,
+                    true,
+                    encoding,
+                    stopped,
+                    error_policy,
+                );
+            }
+        }
+    }
 
-        let (s, r) = crossbeam_channel::bounded(1);
-        buffer_slice.map_async(MapMode::Read, move |r| match r {
-            Ok(r) => s.send(r).expect("Failed to send map update"),
-            Err(err) => panic!("Failed to map buffer {err}"),
-        });
-        render_device.poll(Maintain::wait()).panic_on_timeout();
-        r.recv().expect("Failed to receive the map_async message");
+    !capture_state.has_frames_in_flight()
+}
 
-        let buffer_bytes = buffer_slice.get_mapped_range().to_vec();
-        capture_state.target_buffer.unmap();
+/// Passes the oldest frame of a staging ring, which must be mapped, to the encoders.
+fn deliver_frame(
+    staging: &mut StagingRing,
+    target_image: &Image,
+    depth: bool,
+    encoding: &mut Encoding,
+    stopped: &AtomicBool,
+    error_policy: ErrorPolicy,
+) {
+    let Some((slot, context, readback_latency)) = staging.next_mapped() else {
+        return;
+    };
 
-        // We need to ensure that this works regardless of the image dimensions
-        // If the image became wider when copying from the texture to the buffer,
-        // then the data is reduced to its original size when copying from the buffer to the image.
-        let row_bytes = capture_state.target_image.width() as usize
-            * capture_state
-                .target_image
-                .texture_descriptor
-                .format
-                .pixel_size();
-        let aligned_row_bytes = RenderDevice::align_copy_bytes_per_row(row_bytes);
-        if row_bytes == aligned_row_bytes {
-            capture_state
-                .target_image
-                .data
-                .get_or_insert_default()
-                .clone_from(&buffer_bytes);
-        } else {
-            // shrink data to original image size
-            capture_state.target_image.data = Some(
-                buffer_bytes
-                    .chunks(aligned_row_bytes)
-                    .take(capture_state.target_image.height() as usize)
-                    .flat_map(|row| &row[..row_bytes.min(row.len())])
-                    .cloned()
+    // Frames after the capture has been stopped are discarded
+    if !stopped.load(Ordering::Acquire) {
+        let bytes = staging.buffers[slot].buffer.slice(..).get_mapped_range();
+        let frame = frame_view(&bytes, target_image);
+        encoding.encode(&frame, &context, readback_latency, depth, error_policy);
+    }
+    staging.release(slot);
+}
+
+/// Delivers the frames that have been read back for all members of a group as frame sets.
+/// Frames that are missing for any member can't be completed and are dropped for all of them.
+fn encode_group(group: &mut ExtractedGroup) {
+    if group.members.is_empty() {
+        return;
+    }
+
+    loop {
+        let fronts: Vec<_> = group
+            .members
+            .iter_mut()
+            .map(|(_, state)| state.as_mut().and_then(|state| state.staging.front()))
+            .collect();
+
+        // Frames older than the newest front of any member are incomplete. If a member has no
+        // frames in flight, none of the frames of the others can be completed.
+        let newest = fronts
+            .iter()
+            .map(|front| front.map(|(frame, _)| frame))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|frames| frames.into_iter().max())
+            .unwrap_or(u64::MAX);
+
+        let mut dropped = false;
+        for ((_, state), front) in group.members.iter_mut().zip(&fronts) {
+            if let (Some(state), Some((frame, true))) = (state, front) {
+                if *frame < newest {
+                    let (slot, ..) = state.staging.next_mapped().unwrap();
+                    state.staging.release(slot);
+                    dropped = true;
+                }
+            }
+        }
+        if dropped {
+            continue;
+        }
+        if !fronts
+            .iter()
+            .all(|front| matches!(front, Some((frame, true)) if *frame == newest))
+        {
+            break;
+        }
+
+        // Frames after the group has been stopped are discarded
+        let stopped = group.stopped.load(Ordering::Acquire);
+        let mut mapped = Vec::with_capacity(group.members.len());
+        let mut readback_latency = Duration::ZERO;
+        for (_, state) in &mut group.members {
+            let state = state.as_mut().unwrap();
+            let (slot, context, latency) = state.staging.next_mapped().unwrap();
+            readback_latency = readback_latency.max(latency);
+            mapped.push((slot, context));
+        }
+
+        if !stopped {
+            // The views borrow the mapped buffers of all members at once
+            let bytes: Vec<_> = group
+                .members
+                .iter()
+                .zip(&mapped)
+                .map(|((_, state), (slot, _))| {
+                    state.as_ref().unwrap().staging.buffers[*slot]
+                        .buffer
+                        .slice(..)
+                        .get_mapped_range()
+                })
+                .collect();
+            let frames = FrameSet {
+                frame: newest,
+                views: group
+                    .members
+                    .iter()
+                    .zip(&bytes)
+                    .zip(&mapped)
+                    .map(|(((entity, state), bytes), (_, context))| FrameSetView {
+                        entity: *entity,
+                        frame: frame_view(bytes, &state.as_ref().unwrap().target_image),
+                        context,
+                    })
                     .collect(),
-            );
+            };
+            group
+                .encoders
+                .encode(&frames, readback_latency, group.error_policy);
         }
 
-        // Call the encoder
-        for encoder in &mut capture.encoders.0 {
-            if let Err(err) = encoder.encode(&capture_state.target_image) {
-                bevy::log::error!("Failed to encode: {:?}", err);
-            }
+        for ((_, state), (slot, _)) in group.members.iter_mut().zip(&mapped) {
+            state.as_mut().unwrap().staging.release(*slot);
+        }
+    }
+}
+
+/// Returns a view of a mapped staging buffer, with the layout of the given target image. The
+/// rows are padded to the copy alignment.
+fn frame_view<'a>(bytes: &'a [u8], target_image: &Image) -> FrameView<'a> {
+    let format = target_image.texture_descriptor.format;
+    FrameView {
+        width: target_image.width(),
+        height: target_image.height(),
+        stride: RenderDevice::align_copy_bytes_per_row(
+            target_image.width() as usize * format.pixel_size(),
+        ),
+        format,
+        data: bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(rate: CaptureRate, elapsed_ms: impl IntoIterator<Item = u64>) -> Vec<bool> {
+        let mut sampler = Sampler::default();
+        elapsed_ms
+            .into_iter()
+            .map(|ms| sampler.sample(rate, Duration::from_millis(ms)))
+            .collect()
+    }
+
+    #[test]
+    fn every_frame() {
+        assert_eq!(sample(CaptureRate::EveryFrame, [0, 0, 5]), [true; 3]);
+    }
+
+    #[test]
+    fn every_nth_frame() {
+        assert_eq!(
+            sample(CaptureRate::EveryNthFrame(3), [0; 7]),
+            [true, false, false, true, false, false, true]
+        );
+        // 0 captures every frame instead of dividing by zero
+        assert_eq!(sample(CaptureRate::EveryNthFrame(0), [0; 3]), [true; 3]);
+    }
+
+    #[test]
+    fn hz() {
+        // 10 Hz at 40 fps
+        assert_eq!(
+            sample(CaptureRate::Hz(10.0), (0..9).map(|frame| frame * 25)),
+            [true, false, false, false, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn hz_missed_samples() {
+        // The samples at 100 ms and 300 ms are missed, the next one stays on the grid at 400 ms
+        assert_eq!(
+            sample(CaptureRate::Hz(10.0), [0, 350, 375, 400, 450]),
+            [true, true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn hz_invalid() {
+        for hz in [0.0, -10.0, f64::INFINITY, f64::NAN] {
+            assert_eq!(sample(CaptureRate::Hz(hz), [0, 1, 2]), [true; 3]);
         }
     }
 }
@@ -0,0 +1,286 @@
+use super::{copy_to_staging, Captures, StagingRing};
+use bevy::{
+    asset::weak_handle,
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state, prepass::ViewPrepassTextures,
+    },
+    image::TextureFormatPixelInfo,
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            binding_types::{texture_depth_2d, texture_depth_2d_multisampled, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, Buffer,
+            BufferDescriptor, BufferUsages, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            CommandEncoder, Extent3d, FragmentState, LoadOp, MultisampleState, Operations,
+            PipelineCache, PrimitiveState, RenderPassColorAttachment, RenderPassDescriptor,
+            RenderPipelineDescriptor, ShaderStages, SpecializedRenderPipeline,
+            SpecializedRenderPipelines, StoreOp, Texture, TextureDescriptor, TextureDimension,
+            TextureFormat, TextureUsages, TextureView, TextureViewId,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+
+pub(super) const DEPTH_SHADER_HANDLE: Handle<Shader> =
+    weak_handle!("6f0c6a3e-5d0f-4b8e-9a63-0d6c2b7f4e21");
+
+/// The format depth frames are delivered in.
+const DEPTH_FORMAT: TextureFormat = TextureFormat::R32Float;
+
+/// Converts the depth prepass of a camera to linear view space depth.
+///
+/// The prepass depth texture can't be copied from, so it is rendered into a color texture first.
+#[derive(Resource)]
+pub(super) struct DepthPipeline {
+    layout: BindGroupLayout,
+    multisampled_layout: BindGroupLayout,
+}
+
+impl FromWorld for DepthPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "capture_depth_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (texture_depth_2d(), uniform_buffer::<Mat4>(false)),
+            ),
+        );
+        let multisampled_layout = render_device.create_bind_group_layout(
+            "capture_depth_multisampled_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_depth_2d_multisampled(),
+                    uniform_buffer::<Mat4>(false),
+                ),
+            ),
+        );
+
+        Self {
+            layout,
+            multisampled_layout,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for DepthPipeline {
+    /// Whether the depth texture is multisampled.
+    type Key = bool;
+
+    fn specialize(&self, multisampled: Self::Key) -> RenderPipelineDescriptor {
+        let (layout, shader_defs) = if multisampled {
+            (
+                self.multisampled_layout.clone(),
+                vec!["MULTISAMPLED".into()],
+            )
+        } else {
+            (self.layout.clone(), vec![])
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("capture_depth_pipeline".into()),
+            layout: vec![layout],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: DEPTH_SHADER_HANDLE,
+                shader_defs,
+                entry_point: "fs_main".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: DEPTH_FORMAT,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            push_constant_ranges: vec![],
+            zero_initialize_workgroup_memory: false,
+        }
+    }
+}
+
+/// The depth stream of a capture, read back through its own staging ring.
+pub(super) struct DepthCapture {
+    texture: Texture,
+    view: TextureView,
+    /// The inverse projection of the camera, to unproject the depth buffer.
+    uniform: Buffer,
+    /// The bind group and the id of the prepass depth view it was created for.
+    bind_group: Option<(TextureViewId, BindGroup)>,
+    pipeline: Option<CachedRenderPipelineId>,
+    pub(super) staging: StagingRing,
+    /// Describes the size and format of the depth frames.
+    pub(super) target_image: Image,
+}
+
+impl DepthCapture {
+    pub(super) fn new(size: Extent3d, render_device: &RenderDevice) -> Self {
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("capture_depth_texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&Default::default());
+        let uniform = render_device.create_buffer(&BufferDescriptor {
+            label: Some("capture_depth_uniform"),
+            size: size_of::<Mat4>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let padded_bytes_per_row =
+            RenderDevice::align_copy_bytes_per_row(size.width as usize * DEPTH_FORMAT.pixel_size());
+        let staging = StagingRing::new(
+            padded_bytes_per_row as u64 * size.height as u64,
+            render_device,
+        );
+
+        Self {
+            texture,
+            view,
+            uniform,
+            bind_group: None,
+            pipeline: None,
+            staging,
+            target_image: Image::new_uninit(
+                size,
+                TextureDimension::D2,
+                DEPTH_FORMAT,
+                RenderAssetUsages::default(),
+            ),
+        }
+    }
+
+    /// Renders the linear depth and copies it into the staging buffer of this frame, if any.
+    pub(super) fn render(&self, encoder: &mut CommandEncoder, pipeline_cache: &PipelineCache) {
+        let (Some(slot), Some((_, bind_group))) = (self.staging.write, &self.bind_group) else {
+            return;
+        };
+        let Some(pipeline) = self
+            .pipeline
+            .and_then(|pipeline| pipeline_cache.get_render_pipeline(pipeline))
+        else {
+            return;
+        };
+
+        {
+            let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("capture_depth_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &self.view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Default::default()),
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        copy_to_staging(
+            encoder,
+            &self.texture,
+            UVec2::ZERO,
+            self.target_image.texture_descriptor.size,
+            DEPTH_FORMAT,
+            &self.staging.buffers[slot].buffer,
+        );
+    }
+}
+
+/// Prepares the depth pass of the captures that reserved a frame. If the depth can't be
+/// rendered yet, its staging buffer is given back and the color frame is captured alone.
+pub(super) fn prepare_depth_captures(
+    mut captures: ResMut<Captures>,
+    prepass_textures: Query<&ViewPrepassTextures>,
+    depth_pipeline: Res<DepthPipeline>,
+    mut depth_pipelines: ResMut<SpecializedRenderPipelines<DepthPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    for capture in captures.captures.values_mut() {
+        if !capture.is_active() {
+            continue;
+        }
+        let Some(capture_state) = &mut capture.state else {
+            continue;
+        };
+        let Some(depth) = &mut capture_state.depth else {
+            continue;
+        };
+        if depth.staging.write.is_none() {
+            continue;
+        }
+
+        let Some(depth_texture) = prepass_textures
+            .get(capture_state.render_camera)
+            .ok()
+            .and_then(|textures| textures.depth.as_ref())
+        else {
+            bevy::log::warn_once!("Capturing depth requires a DepthPrepass on the camera");
+            depth.staging.write = None;
+            continue;
+        };
+        if depth_texture.texture.texture.size() != depth.target_image.texture_descriptor.size {
+            bevy::log::debug!("The depth prepass doesn't match the capture size, skipping frame");
+            depth.staging.write = None;
+            continue;
+        }
+
+        let multisampled = depth_texture.texture.texture.sample_count() > 1;
+        let pipeline = depth_pipelines.specialize(&pipeline_cache, &depth_pipeline, multisampled);
+        depth.pipeline = Some(pipeline);
+        if pipeline_cache.get_render_pipeline(pipeline).is_none() {
+            depth.staging.write = None;
+            continue;
+        }
+
+        let view_from_clip = capture_state.context.projection.inverse();
+        render_queue.write_buffer(
+            &depth.uniform,
+            0,
+            &view_from_clip
+                .to_cols_array()
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect::<Vec<_>>(),
+        );
+
+        // The prepass texture is only recreated on resize or a change of the sample count
+        let view = &depth_texture.texture.default_view;
+        if depth
+            .bind_group
+            .as_ref()
+            .is_some_and(|(view_id, _)| *view_id == view.id())
+        {
+            continue;
+        }
+        let layout = if multisampled {
+            &depth_pipeline.multisampled_layout
+        } else {
+            &depth_pipeline.layout
+        };
+        let bind_group = render_device.create_bind_group(
+            "capture_depth_bind_group",
+            layout,
+            &BindGroupEntries::sequential((view, depth.uniform.as_entire_binding())),
+        );
+        depth.bind_group = Some((view.id(), bind_group));
+    }
+}
@@ -0,0 +1,18 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+#ifdef MULTISAMPLED
+@group(0) @binding(0) var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0) var depth_texture: texture_depth_2d;
+#endif
+@group(0) @binding(1) var<uniform> view_from_clip: mat4x4<f32>;
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let depth = textureLoad(depth_texture, vec2<i32>(in.position.xy), 0);
+
+    // Unproject to view space, the camera looks along -z
+    let ndc = vec4(in.uv.x * 2.0 - 1.0, 1.0 - in.uv.y * 2.0, depth, 1.0);
+    let view = view_from_clip * ndc;
+    return vec4(-view.z / view.w, 0.0, 0.0, 1.0);
+}
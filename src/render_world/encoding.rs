@@ -0,0 +1,679 @@
+use crate::{
+    encoder::{self, FrameContext, FrameFormat, FrameSet, FrameView},
+    events::{CaptureEvent, CaptureEventSender, CaptureFinished, EncodeFailed, FrameCaptured},
+    stats::FrameStats,
+    EncodeQueue, Encoders, ErrorPolicy, GroupEncoders, QueueOverflow,
+};
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender, TrySendError};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Runs the encoders of a capture, either inline or on a worker thread.
+pub(super) enum Encoding {
+    Inline(CaptureEncoders),
+    Worker(EncodeWorker),
+}
+
+impl Encoding {
+    pub(super) fn new(
+        encoders: CaptureEncoders,
+        encode_queue: Option<EncodeQueue>,
+        dropped_frames: Arc<AtomicU64>,
+    ) -> Self {
+        match encode_queue {
+            Some(encode_queue) => {
+                Self::Worker(EncodeWorker::spawn(encoders, encode_queue, dropped_frames))
+            }
+            None => Self::Inline(encoders),
+        }
+    }
+
+    pub(super) fn has_depth(&self) -> bool {
+        match self {
+            Self::Inline(encoders) => encoders.depth_encoders.is_some(),
+            Self::Worker(worker) => worker.has_depth,
+        }
+    }
+
+    pub(super) fn encode(
+        &mut self,
+        frame: &FrameView<'_>,
+        context: &FrameContext,
+        readback_latency: Duration,
+        depth: bool,
+        policy: ErrorPolicy,
+    ) {
+        match self {
+            Self::Inline(encoders) => {
+                encoders.encode(frame, context, readback_latency, depth, policy)
+            }
+            Self::Worker(worker) => {
+                // The frame must outlive the mapping of the staging buffer, this is the only
+                // copy and its allocation is reused
+                let frame = worker.own(frame);
+                worker.send(EncodeJob {
+                    frame,
+                    context: context.clone(),
+                    readback_latency,
+                    depth,
+                    policy,
+                });
+            }
+        }
+    }
+
+    /// Marks the encoders as replaced by new ones, so finishing them doesn't finish the capture.
+    pub(super) fn replaced(&self) {
+        let replaced = match self {
+            Self::Inline(encoders) => &encoders.reporter.replaced,
+            Self::Worker(worker) => &worker.replaced,
+        };
+        replaced.store(true, Ordering::Release);
+    }
+
+    /// Blocks until the frames queued for the worker thread have been encoded.
+    pub(super) fn flush(&self) {
+        if let Self::Worker(worker) = self {
+            worker.flush();
+        }
+    }
+}
+
+/// The encoders of a capture. Applies the [`ErrorPolicy`] and reports the results as events.
+///
+/// Dropping it finishes the encoders.
+pub(super) struct CaptureEncoders {
+    encoders: Encoders,
+    depth_encoders: Option<Encoders>,
+    consecutive_errors: u32,
+    consecutive_depth_errors: u32,
+    /// The format of the last frame, to notify the encoders when it changes.
+    format: Option<FrameFormat>,
+    depth_format: Option<FrameFormat>,
+    limit: Option<FrameLimit>,
+    /// The number of frames passed to the encoders.
+    frames: u64,
+    /// The id of the final frame once the limit has been reached.
+    last_frame: Option<u64>,
+    reporter: Reporter,
+}
+
+/// The number of frames a capture is limited to, see
+/// [`Capture::capture_frames`](crate::Capture::capture_frames).
+pub(super) struct FrameLimit {
+    pub(super) frames: u64,
+    /// Shared with the [`Capture`](crate::Capture), set once the final frame was encoded.
+    pub(super) completed: Arc<AtomicBool>,
+}
+
+impl CaptureEncoders {
+    pub(super) fn new(
+        entity: Entity,
+        encoders: Encoders,
+        depth_encoders: Option<Encoders>,
+        limit: Option<FrameLimit>,
+        stopped: Arc<AtomicBool>,
+        events: CaptureEventSender,
+    ) -> Self {
+        Self {
+            encoders,
+            depth_encoders,
+            consecutive_errors: 0,
+            consecutive_depth_errors: 0,
+            format: None,
+            depth_format: None,
+            limit,
+            frames: 0,
+            last_frame: None,
+            reporter: Reporter {
+                entity,
+                stopped,
+                events,
+                replaced: Default::default(),
+            },
+        }
+    }
+
+    /// Passes a frame to the encoders, unless the capture has been stopped or has reached its
+    /// frame limit.
+    fn encode(
+        &mut self,
+        frame: &FrameView<'_>,
+        context: &FrameContext,
+        readback_latency: Duration,
+        depth: bool,
+        policy: ErrorPolicy,
+    ) {
+        if self.reporter.is_stopped() {
+            return;
+        }
+        // Frames in flight after the final frame are discarded, its depth is still encoded
+        if self
+            .last_frame
+            .is_some_and(|last_frame| context.frame > last_frame)
+        {
+            return;
+        }
+
+        let (encoders, consecutive_errors, last_format) = if depth {
+            match &mut self.depth_encoders {
+                Some(depth_encoders) => (
+                    depth_encoders,
+                    &mut self.consecutive_depth_errors,
+                    &mut self.depth_format,
+                ),
+                None => return,
+            }
+        } else {
+            (
+                &mut self.encoders,
+                &mut self.consecutive_errors,
+                &mut self.format,
+            )
+        };
+
+        let format = frame.frame_format();
+        if let Some(from) = last_format.replace(format).filter(|from| *from != format) {
+            self.reporter.format_changed(
+                &mut encoders.0,
+                |encoder| encoder.format_changed(from, format),
+                |encoder| encoder.finish(),
+                policy,
+            );
+        }
+
+        let (failed, encode_times) = self.reporter.encode_each(
+            &mut encoders.0,
+            |encoder| encoder.encode_view(frame, context),
+            |encoder| encoder.finish(),
+            context.frame,
+            policy,
+            depth,
+        );
+        self.reporter
+            .count_errors(failed, consecutive_errors, policy);
+
+        if !depth {
+            self.reporter
+                .frame_captured(context.frame, readback_latency, encode_times);
+
+            self.frames += 1;
+            if let Some(limit) = &self.limit {
+                if self.frames == limit.frames {
+                    self.last_frame = Some(context.frame);
+                    limit.completed.store(true, Ordering::Release);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CaptureEncoders {
+    fn drop(&mut self) {
+        // Finish the encoders before reporting it
+        let depth_encoders = self
+            .depth_encoders
+            .as_mut()
+            .map(|encoders| std::mem::take(&mut encoders.0));
+        for encoder in std::mem::take(&mut self.encoders.0)
+            .into_iter()
+            .chain(depth_encoders.into_iter().flatten())
+        {
+            self.reporter.encoder_finished(encoder.finish());
+        }
+
+        self.reporter.capture_finished();
+    }
+}
+
+/// The encoders of a capture group. Applies the [`ErrorPolicy`] and reports the results as
+/// events, like [`CaptureEncoders`].
+pub(super) struct CaptureGroupEncoders {
+    encoders: GroupEncoders,
+    consecutive_errors: u32,
+    /// The formats of the views of the last frame set, to notify the encoders when they change.
+    formats: Vec<FrameFormat>,
+    reporter: Reporter,
+}
+
+impl CaptureGroupEncoders {
+    pub(super) fn new(
+        entity: Entity,
+        encoders: GroupEncoders,
+        stopped: Arc<AtomicBool>,
+        events: CaptureEventSender,
+    ) -> Self {
+        Self {
+            encoders,
+            consecutive_errors: 0,
+            formats: Vec::new(),
+            reporter: Reporter {
+                entity,
+                stopped,
+                events,
+                replaced: Default::default(),
+            },
+        }
+    }
+
+    /// Marks the encoders as replaced by new ones, so finishing them doesn't finish the group.
+    pub(super) fn replaced(&self) {
+        self.reporter.replaced.store(true, Ordering::Release);
+    }
+
+    /// Passes a frame set to the encoders, unless the group has been stopped.
+    pub(super) fn encode(
+        &mut self,
+        frames: &FrameSet<'_>,
+        readback_latency: Duration,
+        policy: ErrorPolicy,
+    ) {
+        if self.reporter.is_stopped() {
+            return;
+        }
+
+        let formats: Vec<_> = frames
+            .views
+            .iter()
+            .map(|view| view.frame.frame_format())
+            .collect();
+        for (view, (from, to)) in self.formats.iter().zip(&formats).enumerate() {
+            if from != to {
+                self.reporter.format_changed(
+                    &mut self.encoders.0,
+                    |encoder| encoder.format_changed(view, *from, *to),
+                    |encoder| encoder.finish(),
+                    policy,
+                );
+            }
+        }
+        self.formats = formats;
+
+        let (failed, encode_times) = self.reporter.encode_each(
+            &mut self.encoders.0,
+            |encoder| encoder.encode_set(frames),
+            |encoder| encoder.finish(),
+            frames.frame,
+            policy,
+            false,
+        );
+        self.reporter
+            .count_errors(failed, &mut self.consecutive_errors, policy);
+        self.reporter
+            .frame_captured(frames.frame, readback_latency, encode_times);
+    }
+}
+
+impl Drop for CaptureGroupEncoders {
+    fn drop(&mut self) {
+        // Finish the encoders before reporting it
+        for encoder in std::mem::take(&mut self.encoders.0) {
+            self.reporter.encoder_finished(encoder.finish());
+        }
+
+        self.reporter.capture_finished();
+    }
+}
+
+/// Applies the [`ErrorPolicy`] of a capture or group and reports the results as events.
+struct Reporter {
+    entity: Entity,
+    /// Shared with the [`Capture`](crate::Capture) or [`CaptureGroup`](crate::CaptureGroup), set
+    /// once it was stopped by the error policy.
+    stopped: Arc<AtomicBool>,
+    events: CaptureEventSender,
+    /// Set when the encoders were replaced by new ones while capturing, which doesn't finish
+    /// the capture.
+    replaced: Arc<AtomicBool>,
+}
+
+impl Reporter {
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+
+    /// Sends [`CaptureFinished`], unless the encoders were replaced.
+    fn capture_finished(&self) {
+        if !self.replaced.load(Ordering::Acquire) {
+            self.finished();
+        }
+    }
+
+    /// Passes a frame to each of the encoders and handles their errors according to the policy.
+    /// Returns `true` if any of them failed, and the time each encoder took.
+    fn encode_each<E: ?Sized>(
+        &self,
+        encoders: &mut Vec<Box<E>>,
+        mut encode: impl FnMut(&mut E) -> encoder::Result<()>,
+        finish: impl Fn(Box<E>) -> encoder::Result<()>,
+        frame: u64,
+        policy: ErrorPolicy,
+        depth: bool,
+    ) -> (bool, Vec<Duration>) {
+        let entity = self.entity;
+        let mut failed = false;
+        let mut encode_times = Vec::with_capacity(encoders.len());
+        let mut index = 0;
+        while index < encoders.len() {
+            let start = Instant::now();
+            let result = encode(&mut encoders[index]);
+            encode_times.push(start.elapsed());
+            let Err(err) = result else {
+                index += 1;
+                continue;
+            };
+            failed = true;
+
+            if policy == ErrorPolicy::Panic {
+                panic!("Failed to encode frame {frame} of {entity}: {err}");
+            }
+            if depth {
+                bevy::log::error!("Failed to encode depth: {:?}", err);
+            } else {
+                bevy::log::error!("Failed to encode: {:?}", err);
+            }
+            self.events.send(CaptureEvent::EncodeFailed(EncodeFailed {
+                entity,
+                error: err,
+            }));
+
+            if policy == ErrorPolicy::DropEncoder {
+                bevy::log::warn!("Dropping the failing encoder of {entity}");
+                self.encoder_finished(finish(encoders.remove(index)));
+            } else {
+                index += 1;
+            }
+        }
+        (failed, encode_times)
+    }
+
+    /// Notifies the encoders that the format of the frames changed. Encoders that can't handle
+    /// the change are finished and removed, regardless of the policy unless it panics.
+    fn format_changed<E: ?Sized>(
+        &self,
+        encoders: &mut Vec<Box<E>>,
+        mut notify: impl FnMut(&mut E) -> encoder::Result<()>,
+        finish: impl Fn(Box<E>) -> encoder::Result<()>,
+        policy: ErrorPolicy,
+    ) {
+        let entity = self.entity;
+        let mut index = 0;
+        while index < encoders.len() {
+            let Err(err) = notify(&mut encoders[index]) else {
+                index += 1;
+                continue;
+            };
+
+            if policy == ErrorPolicy::Panic {
+                panic!("An encoder of {entity} can't handle the format change: {err}");
+            }
+            bevy::log::error!(
+                "Dropping an encoder of {entity}, it can't handle the format change: {:?}",
+                err
+            );
+            self.events.send(CaptureEvent::EncodeFailed(EncodeFailed {
+                entity,
+                error: err,
+            }));
+            self.encoder_finished(finish(encoders.remove(index)));
+        }
+    }
+
+    /// Counts consecutive failed frames and stops after too many for [`ErrorPolicy::StopAfter`].
+    /// Only a failed frame can stop the capture.
+    fn count_errors(&self, failed: bool, consecutive_errors: &mut u32, policy: ErrorPolicy) {
+        *consecutive_errors = if failed { *consecutive_errors + 1 } else { 0 };
+        if let ErrorPolicy::StopAfter(max) = policy {
+            if failed && *consecutive_errors >= max {
+                bevy::log::error!(
+                    "Stopping the capture of {} after {} consecutive errors",
+                    self.entity,
+                    consecutive_errors
+                );
+                self.stopped.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Reports the error of an encoder that could not be finished, e.g. a video that could not
+    /// be written.
+    fn encoder_finished(&self, result: encoder::Result<()>) {
+        if let Err(err) = result {
+            bevy::log::error!("Failed to finish an encoder of {}: {:?}", self.entity, err);
+            self.events.send(CaptureEvent::EncodeFailed(EncodeFailed {
+                entity: self.entity,
+                error: err,
+            }));
+        }
+    }
+
+    fn frame_captured(&self, frame: u64, readback_latency: Duration, encode_times: Vec<Duration>) {
+        self.events.send(CaptureEvent::FrameCaptured(FrameCaptured {
+            entity: self.entity,
+            frame,
+        }));
+        self.events.send(CaptureEvent::Stats(FrameStats {
+            entity: self.entity,
+            delivered: Instant::now(),
+            readback_latency,
+            encode_times,
+        }));
+    }
+
+    fn finished(&self) {
+        self.events.send(CaptureEvent::Finished(CaptureFinished {
+            entity: self.entity,
+        }));
+    }
+}
+
+struct EncodeJob {
+    frame: OwnedFrame,
+    context: FrameContext,
+    readback_latency: Duration,
+    depth: bool,
+    policy: ErrorPolicy,
+}
+
+/// A copy of a [`FrameView`] for the worker thread.
+struct OwnedFrame {
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: TextureFormat,
+    data: Vec<u8>,
+}
+
+impl OwnedFrame {
+    fn view(&self) -> FrameView<'_> {
+        FrameView {
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            format: self.format,
+            data: &self.data,
+        }
+    }
+}
+
+/// A worker thread running the encoders of a capture, fed by a bounded queue.
+///
+/// Dropping it waits until the queued frames have been encoded and the encoders are finished.
+pub(super) struct EncodeWorker {
+    sender: Option<Sender<EncodeJob>>,
+    /// Used to drop the oldest frame if the queue is full.
+    receiver: Receiver<EncodeJob>,
+    overflow: QueueOverflow,
+    dropped_frames: Arc<AtomicU64>,
+    has_depth: bool,
+    /// Shared with the reporter of the encoders, see [`Encoding::replaced`].
+    replaced: Arc<AtomicBool>,
+    /// The number of frames sent to the worker, and the number it has encoded.
+    sent: u64,
+    encoded: Arc<(Mutex<u64>, Condvar)>,
+    /// The buffers of encoded frames, handed back by the worker to be reused.
+    recycled: Receiver<Vec<u8>>,
+    /// Hands back the buffers of dropped frames.
+    recycle: Sender<Vec<u8>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EncodeWorker {
+    fn spawn(
+        mut encoders: CaptureEncoders,
+        encode_queue: EncodeQueue,
+        dropped_frames: Arc<AtomicU64>,
+    ) -> Self {
+        let (sender, receiver) =
+            crossbeam_channel::bounded::<EncodeJob>(encode_queue.capacity.max(1));
+        let has_depth = encoders.depth_encoders.is_some();
+        let replaced = encoders.reporter.replaced.clone();
+
+        let jobs = receiver.clone();
+        let encoded = Arc::new((Mutex::new(0), Condvar::new()));
+        let thread_encoded = encoded.clone();
+        let (recycle, recycled) = crossbeam_channel::bounded(encode_queue.capacity.max(1) + 1);
+        let thread_recycle = recycle.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("capture encoder {}", encoders.reporter.entity))
+            .spawn(move || {
+                for job in jobs {
+                    encoders.encode(
+                        &job.frame.view(),
+                        &job.context,
+                        job.readback_latency,
+                        job.depth,
+                        job.policy,
+                    );
+                    // Dropped if enough buffers are waiting already
+                    let _ = thread_recycle.try_send(job.frame.data);
+
+                    let (count, condvar) = &*thread_encoded;
+                    *count.lock().unwrap() += 1;
+                    condvar.notify_all();
+                }
+            })
+            .expect("Failed to spawn the encoder thread");
+
+        Self {
+            sender: Some(sender),
+            receiver,
+            overflow: encode_queue.overflow,
+            dropped_frames,
+            has_depth,
+            replaced,
+            sent: 0,
+            encoded,
+            recycled,
+            recycle,
+            thread: Some(thread),
+        }
+    }
+
+    /// Copies a frame into a recycled buffer, if there is one.
+    fn own(&self, frame: &FrameView<'_>) -> OwnedFrame {
+        let mut data = self.recycled.try_recv().unwrap_or_default();
+        data.clear();
+        data.extend_from_slice(frame.data);
+        OwnedFrame {
+            width: frame.width,
+            height: frame.height,
+            stride: frame.stride,
+            format: frame.format,
+            data,
+        }
+    }
+
+    fn flush(&self) {
+        let Some(thread) = &self.thread else {
+            return;
+        };
+
+        let (count, condvar) = &*self.encoded;
+        let mut count = count.lock().unwrap();
+        // Stop waiting if the thread panicked, the next frame propagates the panic
+        while *count < self.sent && !thread.is_finished() {
+            count = condvar
+                .wait_timeout(count, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn send(&mut self, mut job: EncodeJob) {
+        // Propagate panics of the encoders, e.g. from ErrorPolicy::Panic
+        if self.thread.as_ref().is_some_and(JoinHandle::is_finished) {
+            if let Err(payload) = self.thread.take().unwrap().join() {
+                std::panic::resume_unwind(payload);
+            }
+        }
+        let (Some(sender), Some(thread)) = (&self.sender, &self.thread) else {
+            self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        match self.overflow {
+            QueueOverflow::Block => loop {
+                match sender.send_timeout(job, Duration::from_millis(100)) {
+                    Ok(()) => {
+                        self.sent += 1;
+                        break;
+                    }
+                    Err(SendTimeoutError::Timeout(rejected)) if !thread.is_finished() => {
+                        job = rejected;
+                    }
+                    Err(_) => {
+                        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            },
+            QueueOverflow::DropNewest => match sender.try_send(job) {
+                Ok(()) => self.sent += 1,
+                Err(error) => {
+                    let _ = self.recycle.try_send(error.into_inner().frame.data);
+                    self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            QueueOverflow::DropOldest => loop {
+                match sender.try_send(job) {
+                    Ok(()) => {
+                        self.sent += 1;
+                        break;
+                    }
+                    Err(TrySendError::Full(rejected)) => {
+                        if let Ok(dropped) = self.receiver.try_recv() {
+                            let _ = self.recycle.try_send(dropped.frame.data);
+                            self.sent -= 1;
+                            self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                        }
+                        job = rejected;
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Drop for EncodeWorker {
+    fn drop(&mut self) {
+        // Closing the queue lets the worker finish the remaining frames and the encoders
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                bevy::log::error!("The encoder thread panicked");
+            }
+        }
+    }
+}
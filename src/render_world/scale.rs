@@ -0,0 +1,170 @@
+use bevy::{
+    asset::weak_handle,
+    core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{texture_2d, uniform_buffer_sized},
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, Buffer,
+            BufferInitDescriptor, BufferUsages, CachedRenderPipelineId, ColorTargetState,
+            ColorWrites, CommandEncoder, Extent3d, FragmentState, LoadOp, MultisampleState,
+            Operations, PipelineCache, PrimitiveState, RenderPassColorAttachment,
+            RenderPassDescriptor, RenderPipelineDescriptor, ShaderStages,
+            SpecializedRenderPipeline, StoreOp, Texture, TextureDescriptor, TextureDimension,
+            TextureFormat, TextureSampleType, TextureUsages, TextureView,
+        },
+        renderer::RenderDevice,
+    },
+};
+
+pub(super) const SCALE_SHADER_HANDLE: Handle<Shader> =
+    weak_handle!("2b8f4c1d-7a3e-4f69-b0d5-9e1c6a8f3b72");
+
+/// Crops and scales the source of a capture into a smaller texture before it is read back.
+#[derive(Resource)]
+pub(super) struct ScalePipeline {
+    layout: BindGroupLayout,
+}
+
+impl FromWorld for ScalePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "capture_scale_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    uniform_buffer_sized(false, None),
+                ),
+            ),
+        );
+
+        Self { layout }
+    }
+}
+
+impl SpecializedRenderPipeline for ScalePipeline {
+    /// The format of the source and the scaled texture.
+    type Key = TextureFormat;
+
+    fn specialize(&self, format: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("capture_scale_pipeline".into()),
+            layout: vec![self.layout.clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: SCALE_SHADER_HANDLE,
+                shader_defs: vec![],
+                entry_point: "fs_main".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            push_constant_ranges: vec![],
+            zero_initialize_workgroup_memory: false,
+        }
+    }
+}
+
+/// The texture a scaled capture is rendered into.
+pub(super) struct ScaledTexture {
+    pub(super) texture: Texture,
+    view: TextureView,
+    /// The region of the source, see `scale.wgsl`.
+    uniform: Buffer,
+    pub(super) pipeline: Option<CachedRenderPipelineId>,
+}
+
+impl ScaledTexture {
+    pub(super) fn new(
+        crop: URect,
+        size: Extent3d,
+        format: TextureFormat,
+        render_device: &RenderDevice,
+    ) -> Self {
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("capture_scaled_texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&Default::default());
+
+        let region = [
+            crop.min.x,
+            crop.min.y,
+            crop.width(),
+            crop.height(),
+            size.width,
+            size.height,
+        ];
+        let uniform = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("capture_scale_uniform"),
+            contents: &region
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect::<Vec<_>>(),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        Self {
+            texture,
+            view,
+            uniform,
+            pipeline: None,
+        }
+    }
+
+    /// Renders the region of the source into the scaled texture. Frames are only captured once
+    /// the pipeline is ready, see `prepare_captures`.
+    pub(super) fn render(
+        &self,
+        encoder: &mut CommandEncoder,
+        source: &TextureView,
+        scale_pipeline: &ScalePipeline,
+        pipeline_cache: &PipelineCache,
+        render_device: &RenderDevice,
+    ) {
+        let Some(pipeline) = self
+            .pipeline
+            .and_then(|pipeline| pipeline_cache.get_render_pipeline(pipeline))
+        else {
+            return;
+        };
+
+        let bind_group = render_device.create_bind_group(
+            "capture_scale_bind_group",
+            &scale_pipeline.layout,
+            &BindGroupEntries::sequential((source, self.uniform.as_entire_binding())),
+        );
+
+        let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("capture_scale_pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: &self.view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Default::default()),
+                    store: StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
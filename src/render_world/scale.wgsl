@@ -0,0 +1,29 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+struct Region {
+    // The cropped rectangle of the source texture
+    origin: vec2<u32>,
+    size: vec2<u32>,
+    // The size of the output texture
+    output_size: vec2<u32>,
+}
+
+@group(0) @binding(0) var source: texture_2d<f32>;
+@group(0) @binding(1) var<uniform> region: Region;
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    // Average all source texels covered by this pixel, or pick the nearest when upscaling
+    let pixel = vec2<u32>(in.position.xy);
+    let start = region.origin + pixel * region.size / region.output_size;
+    let end = max(region.origin + (pixel + 1u) * region.size / region.output_size, start + 1u);
+
+    var sum = vec4(0.0);
+    for (var y = start.y; y < end.y; y++) {
+        for (var x = start.x; x < end.x; x++) {
+            sum += textureLoad(source, vec2(x, y), 0);
+        }
+    }
+    let count = (end - start).x * (end - start).y;
+    return sum / f32(count);
+}
@@ -0,0 +1,196 @@
+//! Rolling statistics of the captures.
+//!
+//! The [`CaptureStats`] resource is updated from the render world with every delivered frame,
+//! so like the [events](crate::events) it lags behind by at least one frame. Add the
+//! [`CaptureStatsLogPlugin`] to log a summary periodically.
+
+use crate::{Capture, CaptureGroup};
+use bevy::{ecs::entity::EntityHashMap, prelude::*, time::common_conditions::on_real_timer};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The number of frames the statistics are computed over.
+const STATS_WINDOW: usize = 120;
+
+/// Rolling statistics of every [`Capture`] and [`CaptureGroup`], over their last 120 frames.
+///
+/// The statistics of an entity are reset when it starts capturing and kept after it stopped.
+#[derive(Resource, Default, Debug)]
+pub struct CaptureStats {
+    captures: EntityHashMap<CaptureStatistics>,
+}
+
+impl CaptureStats {
+    /// Returns the statistics of the given capture or group, if it has captured any frames.
+    pub fn get(&self, entity: Entity) -> Option<&CaptureStatistics> {
+        self.captures.get(&entity)
+    }
+
+    /// Returns the statistics of all captures and groups.
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &CaptureStatistics)> {
+        self.captures
+            .iter()
+            .map(|(entity, statistics)| (*entity, statistics))
+    }
+
+    pub(crate) fn reset(&mut self, entity: Entity) {
+        self.captures.remove(&entity);
+    }
+
+    pub(crate) fn record(&mut self, stats: FrameStats) {
+        let statistics = self.captures.entry(stats.entity).or_default();
+        statistics.frames_captured += 1;
+        if statistics.frames.len() == STATS_WINDOW {
+            statistics.frames.pop_front();
+        }
+        statistics.frames.push_back(stats);
+    }
+}
+
+/// The statistics of a single capture or group.
+#[derive(Default, Debug)]
+pub struct CaptureStatistics {
+    frames: VecDeque<FrameStats>,
+    frames_captured: u64,
+    dropped_frames: u64,
+}
+
+impl CaptureStatistics {
+    /// Returns the number of frames passed to the encoders since the capture started.
+    pub fn frames_captured(&self) -> u64 {
+        self.frames_captured
+    }
+
+    /// Returns the number of dropped frames, see [`Capture::dropped_frames`]. Always `0` for
+    /// groups.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// Returns the rate in frames per second at which frames were actually delivered to the
+    /// encoders, in real time. `0` until two frames have been captured.
+    pub fn fps(&self) -> f64 {
+        let (Some(first), Some(last)) = (self.frames.front(), self.frames.back()) else {
+            return 0.0;
+        };
+        let elapsed = last.delivered.duration_since(first.delivered).as_secs_f64();
+        if elapsed > 0.0 {
+            (self.frames.len() - 1) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the mean time from submitting the copy of a frame until its staging buffer was
+    /// mapped. For groups this is the latency of the slowest member.
+    pub fn readback_latency(&self) -> Option<Duration> {
+        mean(self.frames.iter().map(|frame| frame.readback_latency))
+    }
+
+    /// Returns the mean time the encoder with the given index took to encode a frame.
+    pub fn encode_time(&self, encoder: usize) -> Option<Duration> {
+        mean(
+            self.frames
+                .iter()
+                .filter_map(|frame| frame.encode_times.get(encoder).copied()),
+        )
+    }
+
+    /// Returns the mean encode time of each encoder, in the order of the encoders. Depth
+    /// encoders are not included.
+    pub fn encode_times(&self) -> Vec<Duration> {
+        let encoders = self
+            .frames
+            .back()
+            .map_or(0, |frame| frame.encode_times.len());
+        (0..encoders)
+            .filter_map(|encoder| self.encode_time(encoder))
+            .collect()
+    }
+}
+
+fn mean(durations: impl Iterator<Item = Duration>) -> Option<Duration> {
+    let (sum, count) = durations.fold((Duration::ZERO, 0), |(sum, count), duration| {
+        (sum + duration, count + 1)
+    });
+    (count > 0).then(|| sum / count)
+}
+
+/// The statistics of a single frame, sent from the render world.
+#[derive(Debug)]
+pub(crate) struct FrameStats {
+    pub(crate) entity: Entity,
+    /// When the frame was passed to the encoders.
+    pub(crate) delivered: Instant,
+    pub(crate) readback_latency: Duration,
+    /// The time each encoder took, in the order of the encoders.
+    pub(crate) encode_times: Vec<Duration>,
+}
+
+/// Copies the dropped frames counters of the captures into their statistics.
+pub(crate) fn update_dropped_frames(
+    captures: Query<(Entity, &Capture)>,
+    mut stats: ResMut<CaptureStats>,
+) {
+    for (entity, capture) in &captures {
+        if let Some(statistics) = stats.captures.get_mut(&entity) {
+            statistics.dropped_frames = capture.dropped_frames();
+        }
+    }
+}
+
+/// A plugin that logs a summary of the [`CaptureStats`] periodically. Requires the
+/// [`CapturePlugin`](crate::CapturePlugin).
+pub struct CaptureStatsLogPlugin {
+    /// The interval between summaries, in real time.
+    pub interval: Duration,
+}
+
+impl Default for CaptureStatsLogPlugin {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Plugin for CaptureStatsLogPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            log_capture_stats.run_if(on_real_timer(self.interval)),
+        );
+    }
+}
+
+fn log_capture_stats(
+    stats: Res<CaptureStats>,
+    captures: Query<(Option<&Capture>, Option<&CaptureGroup>)>,
+) {
+    for (entity, statistics) in stats.iter() {
+        // Skip stopped and despawned captures
+        let capturing = captures.get(entity).is_ok_and(|(capture, group)| {
+            capture.is_some_and(Capture::is_capturing)
+                || group.is_some_and(CaptureGroup::is_capturing)
+        });
+        if !capturing {
+            continue;
+        }
+
+        let readback_latency = statistics.readback_latency().unwrap_or_default();
+        let encode_times = statistics
+            .encode_times()
+            .iter()
+            .map(|encode_time| format!("{encode_time:.2?}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!(
+            "Capture {entity}: {:.1} fps, readback {readback_latency:.2?}, encode [{encode_times}], {} captured, {} dropped",
+            statistics.fps(),
+            statistics.frames_captured(),
+            statistics.dropped_frames(),
+        );
+    }
+}